@@ -31,7 +31,7 @@ in
         libopus
         libpulseaudio
       ];
-      buildFeatures = ["audio-as-lib"];
+      buildFeatures = ["pulse"];
       # libpulse-simple-sys links by name if pkgconfig fails,
       # and that results in a a binary that can't be run
       # postFixup = "${lib.getExe libtree} $out/bin/gegensprech";
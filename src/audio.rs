@@ -1,35 +1,160 @@
-#[cfg(not(feature = "audio-as-lib"))]
+mod chime;
 mod cmd;
-#[cfg(feature = "audio-as-lib")]
+mod decode;
+mod dsp;
+mod external;
+mod level;
+#[cfg(feature = "pulse")]
 mod pulse;
-use anyhow::{Context, Result};
+mod vad;
+mod wav;
+use anyhow::{bail, Context, Result};
 use itertools::Itertools;
-use matrix_sdk::ruma::{events::room::message::AudioInfo, UInt};
+use matrix_sdk::ruma::{events::room::message::AudioInfo, OwnedRoomId, UInt};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
 use std::{
 	collections::VecDeque,
-	io::Cursor,
+	fs,
 	ops::ControlFlow,
-	sync::{Arc, Mutex},
+	path::Path,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
 	time::Duration,
 };
 use tokio::{
 	sync::{mpsc, oneshot},
 	task::{spawn_blocking, JoinHandle},
 };
-use tracing::debug;
+use tracing::{debug, info};
 
-use crate::status::{self, AudioStatus};
+pub(crate) use dsp::{process_file as dsp_file, DspStage};
+pub(crate) use external::{speak, transcribe};
+
+use crate::{
+	config::{AudioSettings, PlaybackSettings, RecordingSettings},
+	mtx::Outgoing,
+	queue::{self, Queue},
+	status::{self, AudioStatus, Flash},
+};
 
 static MUTEX: Mutex<()> = Mutex::new(());
+/// How often playback checks whether a recording in the background is done
+const RECORDING_POLL: Duration = Duration::from_millis(250);
+
+pub(crate) trait AudioBackend: Send + Sync {
+	/// Recording is always mono, playback at the same rate
+	fn sample_rate(&self) -> u32;
+	/// Feeds blocks of s16le samples to sample until it breaks
+	fn record(&self, sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()>;
+	/// Like record, but for the loop tape that runs the whole time
+	fn record_continuous(
+		&self,
+		sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>,
+	) -> Result<()> {
+		self.record(sample)
+	}
+	/// Stops early and returns Skipped once abort is set
+	fn play(&self, data: &[i16], channels: u16, abort: &AtomicBool) -> Result<Playback>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Playback {
+	Played,
+	Skipped,
+}
+
+#[derive(Deserialize, clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AudioKind {
+	/// libpulse (only if built with the pulse feature)
+	Pulse,
+	/// Shell out to pacat
+	Pacat,
+	/// Shell out to arecord/aplay, no sound server required
+	Alsa,
+	/// Record from and play to WAV files in a directory
+	WavDir,
+}
+
+static BACKEND: OnceCell<Box<dyn AudioBackend>> = OnceCell::new();
+static BACKEND_INIT: &str = "Audio backend is initialized at start";
+static RECORDING: OnceCell<RecordingSettings> = OnceCell::new();
+static DSP: OnceCell<Vec<DspStage>> = OnceCell::new();
 
-#[cfg(not(feature = "audio-as-lib"))]
-use cmd::SAMPLE_RATE;
-#[cfg(feature = "audio-as-lib")]
-use pulse::SAMPLE_RATE;
+#[tracing::instrument]
+pub(crate) fn init(
+	kind: Option<AudioKind>,
+	settings: &AudioSettings,
+	playback: &PlaybackSettings,
+) -> Result<()> {
+	let default = match cfg!(feature = "pulse") {
+		true => AudioKind::Pulse,
+		false => AudioKind::Pacat,
+	};
+	let kind = kind.or(settings.backend).unwrap_or(default);
+	let backend: Box<dyn AudioBackend> = match kind {
+		#[cfg(feature = "pulse")]
+		AudioKind::Pulse => Box::new(pulse::Pulse),
+		#[cfg(not(feature = "pulse"))]
+		AudioKind::Pulse => bail!("Built without pulse feature, use pacat"),
+		AudioKind::Pacat => Box::new(cmd::Pipe::pacat(settings.rate.unwrap_or(48000))),
+		AudioKind::Alsa => Box::new(cmd::Pipe::alsa(
+			settings.rate.unwrap_or(48000),
+			settings.device.clone(),
+		)),
+		AudioKind::WavDir => Box::new(wav::WavDir::new(
+			settings
+				.dir
+				.clone()
+				.context("wav-dir audio backend needs a directory in config.yaml")?,
+			settings.rate.unwrap_or(16000),
+		)?),
+	};
+	let rate = backend.sample_rate();
+	anyhow::ensure!(
+		[8000, 12000, 16000, 24000, 48000].contains(&rate),
+		"Sample rate {rate} not supported by Opus"
+	);
+	info!(?kind, rate, "audio backend");
+	for stage in &settings.dsp {
+		stage.check(Some(rate))?;
+	}
+	vad::VAD.set(settings.vad.clone()).ok();
+	RECORDING.set(settings.recording.clone()).ok();
+	DSP.set(settings.dsp.clone()).ok();
+	level::PLAYBACK.set(playback.clone()).ok();
+	if let Some(decoder) = &settings.decoder {
+		decode::DECODER.set(decoder.clone()).ok();
+	}
+	if BACKEND.set(backend).is_err() {
+		bail!("Can init audio backend only once");
+	}
+	// Decoding needs the backend's sample rate
+	if let Some(chime) = &playback.chime {
+		chime::init(chime).context("Load chimes")?;
+	}
+	Ok(())
+}
+
+fn backend() -> &'static dyn AudioBackend {
+	&**BACKEND.get().expect(BACKEND_INIT)
+}
+
+pub(crate) fn sample_rate() -> u32 {
+	backend().sample_rate()
+}
 
 pub struct Rec {
 	pub data: Vec<u8>,
 	pub info: AudioInfo,
+	/// Target room, if not the default one
+	pub room: Option<OwnedRoomId>,
+	/// The recording before encoding, at the backend's sample rate.
+	/// None for prepared files, which aren't transcribed.
+	pub pcm: Option<Vec<i16>>,
 }
 
 impl std::fmt::Debug for Rec {
@@ -37,47 +162,96 @@ impl std::fmt::Debug for Rec {
 		f.debug_struct("Rec")
 			.field("data", &format!("[u8; {}]", self.data.len()))
 			.field("info", &self.info)
+			.field("room", &self.room)
 			.finish()
 	}
 }
 
 pub struct RecProc {
-	proc: JoinHandle<Result<Rec>>,
-	done: oneshot::Sender<()>,
+	proc: JoinHandle<Result<()>>,
+	stop: Arc<AtomicBool>,
 }
 
 impl RecProc {
+	/// Sends the recording (or its chunks) to messages when done, silence is dropped.
+	/// With hands_free, the recording also ends once the speaker goes quiet.
 	//#[tracing::instrument]
-	pub fn start() -> Self {
-		let (done, mut cont) = oneshot::channel::<()>();
-		let proc = spawn_blocking(move || {
-			let _guard = MUTEX.lock();
-			let mut recorded = Vec::with_capacity(SAMPLE_RATE as usize * 2);
-			let mut led_guard = None;
-			let sample = |block: &[u8]| {
-				for (b1, b2) in block.iter().tuples() {
-					recorded.push(i16::from_le_bytes([*b1, *b2]))
-				}
-				match cont.try_recv() {
-					Err(oneshot::error::TryRecvError::Empty) => {
-						led_guard.get_or_insert_with(|| status::audio(AudioStatus::Recording));
-						Ok(ControlFlow::Continue(()))
+	pub fn start(
+		hands_free: bool,
+		room: Option<OwnedRoomId>,
+		messages: mpsc::Sender<Outgoing>,
+	) -> Self {
+		let stop = Arc::new(AtomicBool::new(false));
+		let proc = spawn_blocking({
+			let stop = stop.clone();
+			move || {
+				let _guard = MUTEX.lock();
+				let limits = RECORDING.get_or_init(RecordingSettings::default);
+				let rate = sample_rate() as usize;
+				let max = (limits.max_length.as_secs_f64() * rate as f64) as usize;
+				let warn_at =
+					max.saturating_sub((limits.warning.as_secs_f64() * rate as f64) as usize);
+				let send = |pcm: &[i16]| -> Result<()> {
+					match process_recording(pcm) {
+						Some(speech) => {
+							let mut rec = encode_raw(&speech)?;
+							rec.room = room.clone();
+							messages.blocking_send(rec.into())?;
+						}
+						None => info!("Recording was only silence, not sending"),
 					}
-					Ok(()) => Ok(ControlFlow::Break(())),
-					Err(e) => Err(e).context("aborted"),
-				}
-			};
-			#[cfg(feature = "audio-as-lib")]
-			pulse::record(sample)?;
-			#[cfg(not(feature = "audio-as-lib"))]
-			cmd::record(sample)?;
-			Ok(encode_raw(&recorded)?)
+					Ok(())
+				};
+				let mut recorded = Vec::with_capacity(rate * 2);
+				let mut chunks = 1;
+				let mut led_guard = None;
+				let mut end = vad::EndOfSpeech::new();
+				let mut sample = |block: &[u8]| {
+					let start = recorded.len();
+					for (b1, b2) in block.iter().tuples() {
+						recorded.push(i16::from_le_bytes([*b1, *b2]))
+					}
+					if stop.load(Ordering::Relaxed) || (hands_free && end.push(&recorded[start..]))
+					{
+						return Ok(ControlFlow::Break(()));
+					}
+					if recorded.len() >= max {
+						if chunks >= limits.chunks {
+							info!(?limits.max_length, "Recording too long, cut off");
+							return Ok(ControlFlow::Break(()));
+						}
+						debug!(chunks, "Sending chunk of long recording");
+						send(&std::mem::take(&mut recorded))?;
+						chunks += 1;
+					}
+					// Nothing will be lost on the chunk boundaries, warn only before the final cutoff.
+					// Blinking only, a beep would end up in the recording.
+					let blink = chunks >= limits.chunks && recorded.len() >= warn_at;
+					match blink && (recorded.len() - warn_at) / (rate / 4) % 2 == 1 {
+						true => led_guard = None,
+						false => {
+							led_guard.get_or_insert_with(|| status::audio(AudioStatus::Recording));
+						}
+					}
+					Ok(ControlFlow::Continue(()))
+				};
+				backend().record(&mut sample)?;
+				send(&recorded)
+			}
 		});
-		RecProc { done, proc }
+		RecProc { stop, proc }
+	}
+	/// Ends the recording from elsewhere, e.g. when a hands-free one is interrupted
+	pub fn stop_flag(&self) -> Arc<AtomicBool> {
+		self.stop.clone()
 	}
 	#[tracing::instrument(skip(self))]
-	pub async fn finish(self) -> Result<Rec> {
-		self.done.send(()).ok();
+	pub async fn finish(self) -> Result<()> {
+		self.stop.store(true, Ordering::Relaxed);
+		self.wait().await
+	}
+	/// Waits for the recording to end by itself
+	pub async fn wait(self) -> Result<()> {
 		self.proc
 			.await
 			.context("Recording spawn error")?
@@ -87,39 +261,61 @@ impl RecProc {
 
 pub struct LoopTape {
 	tape: Arc<Mutex<VecDeque<u8>>>,
+	bytes: Arc<AtomicUsize>,
+	stop: Arc<AtomicBool>,
+}
+
+fn tape_bytes(duration: Duration) -> usize {
+	(duration.as_secs_f64() * sample_rate() as f64) as usize * 2
 }
 
 impl LoopTape {
 	#[tracing::instrument]
 	pub fn start(duration: Duration) -> Self {
-		let bytes = (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize * 2;
-		let tape = Arc::new(Mutex::new(VecDeque::with_capacity(bytes)));
+		let bytes = Arc::new(AtomicUsize::new(tape_bytes(duration)));
+		let stop = Arc::new(AtomicBool::new(false));
+		let tape = Arc::new(Mutex::new(VecDeque::with_capacity(tape_bytes(duration))));
 		let tape_write = tape.clone();
 		debug!(?bytes, "Loop tape buffer created");
-		spawn_blocking(move || {
-			let sample = |block: &[u8]| {
-				let mut tape = tape_write.lock().expect("Poisoned");
-				assert!(block.len() % 2 == 0);
-				while tape.len() > bytes - block.len() {
-					tape.pop_front();
-				}
-				for &b in block.iter() {
-					tape.push_back(b)
-				}
-				Ok(ControlFlow::Continue(()))
-			};
-			#[cfg(feature = "audio-as-lib")]
-			pulse::record(sample)?;
-			#[cfg(not(feature = "audio-as-lib"))]
-			cmd::record(sample)?;
-			anyhow::Ok(())
+		spawn_blocking({
+			let bytes = bytes.clone();
+			let stop = stop.clone();
+			move || {
+				let mut sample = |block: &[u8]| {
+					if stop.load(Ordering::Relaxed) {
+						return anyhow::Ok(ControlFlow::Break(()));
+					}
+					let mut tape = tape_write.lock().expect("Poisoned");
+					assert!(block.len() % 2 == 0);
+					let bytes = bytes.load(Ordering::Relaxed);
+					while tape.len() > bytes.saturating_sub(block.len()) {
+						tape.pop_front();
+					}
+					for &b in block.iter() {
+						tape.push_back(b)
+					}
+					anyhow::Ok(ControlFlow::Continue(()))
+				};
+				backend().record_continuous(&mut sample)?;
+				anyhow::Ok(())
+			}
 		});
-		LoopTape { tape }
+		LoopTape { tape, bytes, stop }
+	}
+	/// Longer only fills up with time, shorter drops the oldest audio right away
+	#[tracing::instrument(skip(self))]
+	pub fn resize(&self, duration: Duration) {
+		let bytes = tape_bytes(duration);
+		self.bytes.store(bytes, Ordering::Relaxed);
+		let mut tape = self.tape.lock().expect("Poisoned");
+		while tape.len() > bytes {
+			tape.pop_front();
+		}
 	}
 	#[tracing::instrument(skip(self))]
 	pub fn get(&self, duration: Duration) -> Vec<i16> {
 		let tape = self.tape.lock().expect("Poisoned");
-		let bytes = (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize * 2;
+		let bytes = tape_bytes(duration);
 		tape.iter()
 			.rev()
 			.take(bytes)
@@ -131,60 +327,221 @@ impl LoopTape {
 	}
 }
 
-#[tracing::instrument(skip(incoming, background_cmd))]
+impl Drop for LoopTape {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::Relaxed);
+	}
+}
+
+#[tracing::instrument(skip(queue, markers, background_cmd))]
 pub async fn play(
-	mut incoming: mpsc::Receiver<(Vec<u8>, Option<String>, oneshot::Sender<()>)>,
+	queue: Arc<Queue>,
+	rooms: Vec<OwnedRoomId>,
+	markers: mpsc::Sender<queue::Entry>,
 	background_cmd: Arc<Mutex<Option<crate::cmd::Running>>>,
 ) -> Result<()> {
 	loop {
-		let background_cmd = background_cmd.clone();
-		let data = incoming.recv().await;
-		let data = match data {
-			Some(data) => data,
-			None => continue,
+		let mut entry = queue.next().await;
+		// Pending entries stay unread until they're actually played
+		crate::dnd::wait().await;
+		// Playing would end up on a recording, or cut a sequence short, so the message waits
+		let previous = loop {
+			{
+				let mut background = background_cmd.lock().unwrap();
+				if !background
+					.as_ref()
+					.map_or(false, crate::cmd::Running::holds_playback)
+				{
+					break background.take();
+				}
+			}
+			tokio::time::sleep(RECORDING_POLL).await;
 		};
-		let (data, mtyp, played) = data;
-		let mut background_cmd = background_cmd.lock().unwrap();
-		if let Some(background_cmd) = background_cmd.take() {
-			background_cmd.terminate().await;
+		if let Some(previous) = previous {
+			previous.terminate().await;
 		}
-		let proc = spawn_blocking(move || -> Result<_> {
-			let (data, meta) = ogg_opus::decode::<_, 16000>(Cursor::new(data)).context(format!(
-				"Decode {} as OGG Opus",
-				mtyp.as_deref().unwrap_or("MIME unknown")
-			))?;
-			play_raw(&data, meta.channels)?;
-			Ok(())
+		let room_index = match rooms.len() {
+			1 => None,
+			_ => rooms.iter().position(|r| *r == entry.room_id),
+		};
+		let abort = Arc::new(AtomicBool::new(false));
+		let (done, finished) = oneshot::channel();
+		let task = spawn_blocking({
+			let abort = abort.clone();
+			let queue = queue.clone();
+			let entry = entry.clone();
+			move || -> Result<_> {
+				let res = (|| {
+					if let Some(room_index) = room_index {
+						if play_raw(&announcement(room_index), 1, &abort)? == Playback::Skipped {
+							return Ok(Playback::Skipped);
+						}
+					}
+					let data = queue.media(&entry)?;
+					let (data, channels) = decode::decode(entry.mimetype.as_deref(), data)?;
+					let data = level::normalize(data, sample_rate(), channels);
+					let data = chime::prepend(&entry.sender, data, channels);
+					play_raw(&data, channels, &abort)
+				})();
+				let ok = res.is_ok();
+				done.send(res).ok();
+				anyhow::ensure!(ok, "Playback failed");
+				Ok(())
+			}
+		});
+		// Parking the playback where the button can abort it
+		*background_cmd.lock().unwrap() = Some(crate::cmd::Running::Play {
+			task,
+			abort,
+			finish: true,
 		});
-		match proc.await?.context("play") {
-			Ok(()) => {
-				// Unused variable bug?
-				played.send(()).ok();
+		entry.state = match finished.await.context("Player gone")?.context("play") {
+			Ok(playback) => playback.into(),
+			Err(e) => {
+				tracing::error!(?e, "Playback failed");
+				queue::State::Failed
 			}
-			Err(e) => tracing::error!(?e, "Playback failed"),
+		};
+		queue.finish(&entry.event_id, entry.state)?;
+		markers.send(entry).await.ok();
+	}
+}
+
+/// Sine beep, faded in and out to avoid clicks
+pub(crate) fn tone(freq: f32, duration: Duration) -> Vec<i16> {
+	let rate = sample_rate() as f32;
+	let len = (duration.as_secs_f32() * rate) as usize;
+	let fade = rate * 0.01;
+	(0..len)
+		.map(|i| {
+			let env = (i.min(len - i) as f32 / fade).min(1.0);
+			let wave = (2.0 * std::f32::consts::PI * freq * i as f32 / rate).sin();
+			(env * 0.3 * wave * i16::MAX as f32) as i16
+		})
+		.collect()
+}
+
+/// Sound for keying feedback, doesn't block. Left out while something else plays.
+pub(crate) fn cue(cue: Flash) {
+	const SIDETONE: f32 = 700.0;
+	let ms = Duration::from_millis;
+	let pause = vec![0; sample_rate() as usize / 16];
+	let sound = match cue {
+		Flash::Dot => tone(SIDETONE, ms(60)),
+		Flash::Dash => tone(SIDETONE, ms(180)),
+		Flash::Found => [tone(660.0, ms(80)), tone(880.0, ms(120))].concat(),
+		Flash::Unknown => [tone(220.0, ms(120)), pause, tone(220.0, ms(120))].concat(),
+	};
+	std::thread::spawn(move || {
+		let Ok(_guard) = MUTEX.try_lock() else {
+			debug!(?cue, "Audio busy, no cue");
+			return;
+		};
+		backend()
+			.play(&level::apply_volume(&sound), 1, &AtomicBool::new(false))
+			.ok();
+	});
+}
+
+/// Tells rooms apart by pitch when listening to more than one
+fn announcement(room_index: usize) -> Vec<i16> {
+	const NOTES: [f32; 5] = [523.3, 587.3, 659.3, 784.0, 880.0];
+	let mut beep = tone(440.0, Duration::from_millis(100));
+	beep.extend(tone(
+		NOTES[room_index % NOTES.len()],
+		Duration::from_millis(200),
+	));
+	beep
+}
+
+/// Plays finished messages again, their state and read markers stay as they are
+pub(crate) fn replay(
+	queue: &Queue,
+	entries: &[queue::Entry],
+	abort: &AtomicBool,
+) -> Result<Playback> {
+	for entry in entries {
+		let data = queue.media(entry)?;
+		let (data, channels) = decode::decode(entry.mimetype.as_deref(), data)?;
+		let data = level::normalize(data, sample_rate(), channels);
+		let data = chime::prepend(&entry.sender, data, channels);
+		if play_raw(&data, channels, abort)? == Playback::Skipped {
+			return Ok(Playback::Skipped);
 		}
 	}
+	Ok(Playback::Played)
 }
 
-pub(crate) fn play_raw(data: &[i16], channels: u16) -> Result<()> {
+/// Plays a local sound file of any format the decoder knows
+pub(crate) fn play_file(path: &Path, abort: &AtomicBool) -> Result<Playback> {
+	let data = fs::read(path).with_context(|| format!("Read {path:?}"))?;
+	let (data, channels) = decode::decode(None, data)?;
+	play_raw(&data, channels, abort)
+}
+
+/// Runs a recording through the configured DSP chain and trims the silence around the speech.
+/// None if there isn't any speech.
+pub(crate) fn process_recording(pcm: &[i16]) -> Option<Vec<i16>> {
+	let chain = DSP.get().map_or(&[][..], |c| &c[..]);
+	let pcm = dsp::process(chain, pcm, sample_rate());
+	vad::trim(&pcm).map(<[i16]>::to_vec)
+}
+
+/// A local sound file, ready for sending like a recording
+pub(crate) fn load_file(path: &Path) -> Result<Rec> {
+	let original = fs::read(path).with_context(|| format!("Read {path:?}"))?;
+	let ogg = decode::is_ogg(&original);
+	let (data, channels) = decode::decode(None, original.clone())?;
+	let mono = data
+		.chunks_exact(channels as usize)
+		.map(|frame| (frame.iter().map(|&s| s as i32).sum::<i32>() / frame.len() as i32) as i16)
+		.collect::<Vec<_>>();
+	let mut rec = match ogg {
+		// Sent as it is, re-encoding would only lose quality
+		true => rec(original, &mono),
+		false => encode_raw(&mono)?,
+	};
+	rec.pcm = None;
+	Ok(rec)
+}
+
+pub(crate) fn play_raw(data: &[i16], channels: u16, abort: &AtomicBool) -> Result<Playback> {
 	let _guard = MUTEX.lock().unwrap();
-	#[cfg(feature = "audio-as-lib")]
-	pulse::play(data, channels.try_into().context("Insane channel count")?)?;
-	#[cfg(not(feature = "audio-as-lib"))]
-	cmd::play(data, channels)?;
-	Ok(())
+	backend().play(&level::apply_volume(data), channels, abort)
+}
+
+fn encode_opus(recorded: &[i16]) -> Result<Vec<u8>> {
+	let enc = match sample_rate() {
+		8000 => ogg_opus::encode::<8000, 1>(recorded),
+		12000 => ogg_opus::encode::<12000, 1>(recorded),
+		16000 => ogg_opus::encode::<16000, 1>(recorded),
+		24000 => ogg_opus::encode::<24000, 1>(recorded),
+		48000 => ogg_opus::encode::<48000, 1>(recorded),
+		rate => bail!("Unsupported sample rate {rate}"),
+	};
+	Ok(enc?)
 }
 
 pub(crate) fn encode_raw(recorded: &[i16]) -> Result<Rec> {
-	let data = ogg_opus::encode::<SAMPLE_RATE, 1>(&recorded[..]).context("OGG Opus encode")?;
+	let data = encode_opus(recorded).context("OGG Opus encode")?;
+	Ok(rec(data, recorded))
+}
+
+/// OGG data and the mono samples it holds
+fn rec(data: Vec<u8>, pcm: &[i16]) -> Rec {
 	let info = {
 		let mut ai = AudioInfo::new();
 		ai.duration = Some(Duration::from_secs_f64(
-			recorded.len() as f64 / SAMPLE_RATE as f64,
+			pcm.len() as f64 / sample_rate() as f64,
 		));
 		ai.mimetype = Some("media/ogg".to_owned());
 		ai.size = UInt::new(data.len() as u64);
 		ai
 	};
-	Ok(Rec { info, data })
+	Rec {
+		info,
+		data,
+		room: None,
+		pcm: Some(pcm.to_vec()),
+	}
 }
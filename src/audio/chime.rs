@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+use super::{decode, sample_rate, tone};
+use crate::config::Chime;
+
+/// Samples and channel count
+type Sound = (Vec<i16>, u16);
+
+struct Chimes {
+	default: Sound,
+	senders: HashMap<OwnedUserId, Sound>,
+}
+
+static CHIMES: OnceCell<Chimes> = OnceCell::new();
+
+/// Gap between the chime and the message
+const PAUSE: Duration = Duration::from_millis(150);
+
+fn load_file(path: &Path) -> Result<Sound> {
+	let data = fs::read(path).with_context(|| format!("Read chime {path:?}"))?;
+	decode::decode(None, data).with_context(|| format!("Decode chime {path:?}"))
+}
+
+fn ding() -> Sound {
+	let mut ding = tone(660.0, Duration::from_millis(120));
+	ding.extend(tone(880.0, Duration::from_millis(180)));
+	(ding, 1)
+}
+
+/// Decodes all chime files once at start, so a broken one is noticed right away
+pub(super) fn init(settings: &Chime) -> Result<()> {
+	let default = match &settings.default {
+		Some(path) => load_file(path)?,
+		None => ding(),
+	};
+	let senders = settings
+		.senders
+		.iter()
+		.map(|(user, path)| Ok((user.clone(), load_file(path)?)))
+		.collect::<Result<_>>()?;
+	CHIMES.set(Chimes { default, senders }).ok();
+	Ok(())
+}
+
+/// Puts the sender's chime in front of a message, in one piece so the amplifier doesn't doze off in between
+pub(super) fn prepend(sender: &UserId, data: Vec<i16>, channels: u16) -> Vec<i16> {
+	let Some(chimes) = CHIMES.get() else {
+		return data;
+	};
+	let (chime, chime_channels) = chimes.senders.get(sender).unwrap_or(&chimes.default);
+	let (chime_channels, channels) = (*chime_channels as usize, channels as usize);
+	let pause = (PAUSE.as_secs_f64() * sample_rate() as f64) as usize * channels;
+	let mut out = Vec::with_capacity(chime.len() / chime_channels * channels + pause + data.len());
+	// Mixing down to the chime's first channel is plenty for a chime
+	for frame in chime.chunks_exact(chime_channels) {
+		out.extend(std::iter::repeat(frame[0]).take(channels));
+	}
+	out.extend(std::iter::repeat(0).take(pause));
+	out.extend(data);
+	out
+}
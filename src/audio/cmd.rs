@@ -1,3 +1,4 @@
+use super::{AudioBackend, Playback};
 use crate::status;
 use crate::status::AudioStatus;
 use anyhow::{Context, Result};
@@ -8,12 +9,17 @@ use std::{
 	mem,
 	ops::ControlFlow,
 	os::unix::process::ExitStatusExt,
-	process::{Command, Stdio},
+	process::{Command, ExitStatus, Stdio},
+	sync::atomic::{AtomicBool, Ordering},
 	thread,
+	time::Duration,
 };
 use tokio::sync::oneshot;
 use tracing::{debug, warn};
 
+/// How often an abort is checked while the player plays out what it has been sent
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
 static CLIENT_NAME_ARG: &str = concat!("--client-name=", env!("CARGO_PKG_NAME"));
 
 fn read_pipe(mut pipe: impl Read + Send + 'static) -> oneshot::Receiver<Vec<u8>> {
@@ -26,25 +32,106 @@ fn read_pipe(mut pipe: impl Read + Send + 'static) -> oneshot::Receiver<Vec<u8>>
 	receiver
 }
 
-pub(crate) const SAMPLE_RATE: u32 = 48000;
+#[derive(Debug)]
+enum Tool {
+	Pacat,
+	Alsa { device: Option<String> },
+}
+
+/// Shells out to a recorder/player that reads/writes raw s16le
+#[derive(Debug)]
+pub(crate) struct Pipe {
+	tool: Tool,
+	rate: u32,
+}
+
+impl Pipe {
+	pub fn pacat(rate: u32) -> Self {
+		Pipe {
+			tool: Tool::Pacat,
+			rate,
+		}
+	}
+
+	pub fn alsa(rate: u32, device: Option<String>) -> Self {
+		Pipe {
+			tool: Tool::Alsa { device },
+			rate,
+		}
+	}
+
+	fn name(&self, record: bool) -> &'static str {
+		match (&self.tool, record) {
+			(Tool::Pacat, _) => "pacat",
+			(Tool::Alsa { .. }, true) => "arecord",
+			(Tool::Alsa { .. }, false) => "aplay",
+		}
+	}
+
+	fn command(&self, record: bool, channels: u16) -> Command {
+		let mut cmd = Command::new(self.name(record));
+		match &self.tool {
+			Tool::Pacat => {
+				cmd.args([
+					match record {
+						true => "--record",
+						false => "--playback",
+					},
+					CLIENT_NAME_ARG,
+					"--raw",
+					"--format=s16le",
+				]);
+				cmd.arg(format!("--channels={}", channels));
+				cmd.arg(format!("--rate={}", self.rate));
+				if record {
+					cmd.arg("--latency-msec=50");
+				}
+			}
+			Tool::Alsa { device } => {
+				cmd.args(["-q", "-t", "raw", "-f", "S16_LE"]);
+				cmd.arg(format!("--channels={}", channels));
+				cmd.arg(format!("--rate={}", self.rate));
+				if let Some(device) = device {
+					cmd.arg(format!("--device={}", device));
+				}
+			}
+		}
+		cmd
+	}
+
+	/// Whether the recorder exited as expected after being interrupted
+	fn interrupted(&self, exited: &ExitStatus) -> bool {
+		match self.tool {
+			Tool::Pacat => exited.success() || exited.signal() == Some(1),
+			Tool::Alsa { .. } => exited.success() || exited.code() == Some(1),
+		}
+	}
+}
+
+impl AudioBackend for Pipe {
+	fn sample_rate(&self) -> u32 {
+		self.rate
+	}
+
+	fn record(&self, sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()> {
+		record(self, sample)
+	}
+
+	fn play(&self, data: &[i16], channels: u16, abort: &AtomicBool) -> Result<Playback> {
+		play(self, data, channels, abort)
+	}
+}
 
 #[tracing::instrument(skip(sample))]
-pub(crate) fn record(mut sample: impl FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()> {
-	let mut recorder = Command::new("pacat")
-		.args([
-			"--record",
-			CLIENT_NAME_ARG,
-			"--raw",
-			"--format=s16le",
-			"--channels=1",
-			format!("--rate={}", SAMPLE_RATE).as_str(),
-			"--latency-msec=50",
-		])
+fn record(pipe: &Pipe, sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()> {
+	let name = pipe.name(true);
+	let mut recorder = pipe
+		.command(true, 1)
 		.stdin(Stdio::null())
 		.stdout(Stdio::piped())
 		.stderr(Stdio::piped())
 		.spawn()
-		.context("$ pacat --record")?;
+		.with_context(|| format!("$ {name} (record)"))?;
 	let mut stdout = recorder.stdout.take().unwrap();
 	let stderr = read_pipe(recorder.stderr.take().unwrap());
 	loop {
@@ -62,54 +149,68 @@ pub(crate) fn record(mut sample: impl FnMut(&[u8]) -> Result<ControlFlow<()>>) -
 		success = exited.success(),
 		code = exited.code(),
 		signal = exited.signal(),
-		"pacat exited"
+		"{name} exited"
 	);
-	if exited.success() || exited.signal() == Some(1) {
+	if pipe.interrupted(&exited) {
 		Ok(())
 	} else {
 		let stderr = &stderr.blocking_recv().unwrap();
 		let stderr = String::from_utf8_lossy(stderr);
 		let msg = match exited.code() {
-			Some(code) => Cow::Owned(format!("pacat exited with code {}", code)),
-			None => "pacat exited".into(),
+			Some(code) => Cow::Owned(format!("{name} exited with code {}", code)),
+			None => format!("{name} exited").into(),
 		};
 		anyhow::bail!("{}: {}", msg, stderr)
 	}
 }
 
-#[tracing::instrument(skip(data))]
-pub(crate) fn play(data: &[i16], channels: u16) -> Result<()> {
+#[tracing::instrument(skip(data, abort))]
+fn play(pipe: &Pipe, data: &[i16], channels: u16, abort: &AtomicBool) -> Result<Playback> {
 	let data = data
-		.into_iter()
+		.iter()
 		.flat_map(|s| s.to_le_bytes())
 		.collect::<Vec<_>>();
-	// Since we're already shelling out, why not shell out to ffmpeg if the ogg decode fails...
-	// Another day.
-	let mut player = Command::new("pacat")
-		.args([
-			"--playback",
-			CLIENT_NAME_ARG,
-			"--raw",
-			"--format=s16le",
-			"--rate=48000",
-			format!("--channels={}", channels).as_str(),
-		])
+	let name = pipe.name(false);
+	let mut player = pipe
+		.command(false, channels)
 		.stdin(Stdio::piped())
 		.stdout(Stdio::piped())
 		.stderr(Stdio::piped())
 		.spawn()
-		.context("$ pacat --playback")?;
+		.with_context(|| format!("$ {name} (playback)"))?;
 	let stdout = read_pipe(player.stdout.take().unwrap());
 	let stderr = read_pipe(player.stderr.take().unwrap());
 	let mut stdin = player.stdin.take().unwrap();
 	let _guard = status::audio(AudioStatus::Playing);
-	let written = stdin.write_all(&data);
-	if written.is_err() {
-		warn!(?written, "pipe write error, kill pacat");
+	// Write in small pieces so an abort is noticed once the pipe has drained a bit
+	let mut aborted = false;
+	let written = data.chunks(4096).try_for_each(|chunk| {
+		aborted = aborted || abort.load(Ordering::Relaxed);
+		match aborted {
+			true => Ok(()),
+			false => stdin.write_all(chunk),
+		}
+	});
+	if aborted {
+		debug!("playback aborted, kill {name}");
+		player.kill().ok();
+	} else if written.is_err() {
+		warn!(?written, "pipe write error, kill {name}");
 		player.kill().ok();
 	};
 	mem::drop(stdin);
-	let exited = player.wait().context("Process exit waiting failure")?;
+	// The player still has up to a pipe buffer and its own buffer to go
+	let exited = loop {
+		if let Some(exited) = player.try_wait().context("Process exit waiting failure")? {
+			break exited;
+		}
+		if !aborted && abort.load(Ordering::Relaxed) {
+			debug!("playback aborted while draining, kill {name}");
+			aborted = true;
+			player.kill().ok();
+		}
+		thread::sleep(DRAIN_POLL);
+	};
 	let stdout = &stdout.blocking_recv().unwrap();
 	let stdout = String::from_utf8_lossy(stdout);
 	let stderr = &stderr.blocking_recv().unwrap();
@@ -123,9 +224,12 @@ pub(crate) fn play(data: &[i16], channels: u16) -> Result<()> {
 		success = exited.success(),
 		code = exited.code(),
 		signal = exited.signal(),
-		"pacat exited"
+		"{name} exited"
 	);
-	written.context("Write to pacat")?;
+	if aborted {
+		return Ok(Playback::Skipped);
+	}
+	written.with_context(|| format!("Write to {name}"))?;
 	if !exited.success() {
 		let msg = match (stdout.is_empty(), stderr.is_empty()) {
 			(false, false) => format!("Msg:\n{}Err:\n{}", stdout, stderr).into(),
@@ -135,5 +239,5 @@ pub(crate) fn play(data: &[i16], channels: u16) -> Result<()> {
 		};
 		anyhow::bail!("{}", msg);
 	}
-	Ok(())
+	Ok(Playback::Played)
 }
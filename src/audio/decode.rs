@@ -0,0 +1,329 @@
+use anyhow::{bail, Context, Result};
+use once_cell::sync::OnceCell;
+use std::{f64::consts::PI, io::Cursor};
+use tracing::{debug, warn};
+
+use super::{external, sample_rate, wav::Wav};
+
+/// Set from config.yaml at start: gets the file on stdin, should write WAV to stdout.
+pub(super) static DECODER: OnceCell<Vec<String>> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+	Opus,
+	Wav,
+	/// Decoded with symphonia, the file extension it would have is a hint for the probe
+	Native(&'static str),
+	/// Recognized, but needs the external decoder
+	Other(&'static str),
+	Unknown,
+}
+
+/// Content first, clients are not always honest about the mimetype
+fn sniff(mimetype: Option<&str>, data: &[u8]) -> Format {
+	let at = |offset: usize, magic: &[u8]| data.get(offset..offset + magic.len()) == Some(magic);
+	if at(0, b"OggS") {
+		return match () {
+			_ if at(28, b"OpusHead") => Format::Opus,
+			_ if at(28, b"\x01vorbis") => Format::Native("ogg"),
+			_ if at(28, b"\x7fFLAC") => Format::Native("ogg"),
+			_ => Format::Other("OGG"),
+		};
+	}
+	if at(0, b"RIFF") && at(8, b"WAVE") {
+		return Format::Wav;
+	}
+	if at(0, b"fLaC") {
+		return Format::Native("flac");
+	}
+	// Both start with a frame sync, ADTS has the layer bits cleared
+	if data.len() > 1 && data[0] == 0xff && data[1] & 0xf6 == 0xf0 {
+		return Format::Native("aac");
+	}
+	if at(0, b"ID3") || (data.len() > 1 && data[0] == 0xff && data[1] & 0xe0 == 0xe0) {
+		return Format::Native("mp3");
+	}
+	if at(4, b"ftyp") {
+		return Format::Native("m4a");
+	}
+	if at(0, b"\x1a\x45\xdf\xa3") {
+		return Format::Other("Matroska");
+	}
+	match mimetype.map(|m| m.split(';').next().unwrap_or(m).trim()) {
+		Some("media/ogg" | "audio/ogg" | "audio/opus") => Format::Opus,
+		Some("audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave") => Format::Wav,
+		Some("audio/mpeg" | "audio/mp3") => Format::Native("mp3"),
+		Some("audio/mp4" | "audio/x-m4a" | "audio/m4a") => Format::Native("m4a"),
+		Some("audio/aac" | "audio/aacp") => Format::Native("aac"),
+		Some("audio/flac" | "audio/x-flac") => Format::Native("flac"),
+		_ => Format::Unknown,
+	}
+}
+
+pub(super) fn is_ogg(data: &[u8]) -> bool {
+	data.starts_with(b"OggS")
+}
+
+/// Decodes to interleaved samples at the backend's sample rate
+#[tracing::instrument(skip(data), fields(len = data.len()))]
+pub(super) fn decode(mimetype: Option<&str>, data: Vec<u8>) -> Result<(Vec<i16>, u16)> {
+	let format = sniff(mimetype, &data);
+	debug!(?format);
+	let native = match format {
+		Format::Opus => decode_opus(&data).context("Decode OGG Opus"),
+		Format::Wav => decode_wav(&data).context("Decode WAV"),
+		Format::Native(ext) => decode_native(data.clone(), ext)
+			.map(resampled)
+			.context("Decode"),
+		Format::Other(name) => Err(anyhow::anyhow!("No built-in {name} decoder")),
+		Format::Unknown => Err(anyhow::anyhow!(
+			"Unknown format ({})",
+			mimetype.unwrap_or("MIME unknown")
+		)),
+	};
+	match (native, DECODER.get()) {
+		(Ok(decoded), _) => Ok(decoded),
+		(Err(e), Some(cmd)) => {
+			warn!("{e:#}, trying external decoder");
+			let wav = external::filter(cmd, data).context("External decoder")?;
+			decode_wav(&wav).context("Decode external decoder output")
+		}
+		(Err(e), None) => Err(e.context("No decoder configured in config.yaml")),
+	}
+}
+
+fn decode_wav(data: &[u8]) -> Result<(Vec<i16>, u16)> {
+	Wav::parse(data).map(resampled)
+}
+
+fn resampled(wav: Wav) -> (Vec<i16>, u16) {
+	let samples = resample(&wav.samples, wav.channels, wav.rate, sample_rate());
+	(samples, wav.channels)
+}
+
+/// MP3, AAC (ADTS or MP4), FLAC and OGG Vorbis
+fn decode_native(data: Vec<u8>, ext: &str) -> Result<Wav> {
+	use symphonia::core::{
+		audio::SampleBuffer, codecs::DecoderOptions, errors::Error, formats::FormatOptions,
+		io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+	};
+	let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+	let mut format = symphonia::default::get_probe()
+		.format(
+			Hint::new().with_extension(ext),
+			source,
+			&FormatOptions::default(),
+			&MetadataOptions::default(),
+		)
+		.context("Unrecognized container")?
+		.format;
+	let track = format.default_track().context("No audio track")?;
+	let track_id = track.id;
+	let mut decoder = symphonia::default::get_codecs()
+		.make(&track.codec_params, &DecoderOptions::default())
+		.context("Unsupported codec")?;
+	let mut spec = None;
+	let mut samples = vec![];
+	loop {
+		let packet = match format.next_packet() {
+			Ok(packet) => packet,
+			Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+			Err(e) => return Err(e).context("Read packet"),
+		};
+		if packet.track_id() != track_id {
+			continue;
+		}
+		let decoded = match decoder.decode(&packet) {
+			Ok(decoded) => decoded,
+			Err(Error::DecodeError(e)) => {
+				warn!(e, "Skipping undecodable packet");
+				continue;
+			}
+			Err(e) => return Err(e).context("Decode packet"),
+		};
+		spec.get_or_insert(*decoded.spec());
+		let mut buf = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+		buf.copy_interleaved_ref(decoded);
+		samples.extend_from_slice(buf.samples());
+	}
+	let spec = spec.context("Nothing decoded")?;
+	Ok(Wav {
+		rate: spec.rate,
+		channels: spec.channels.count() as u16,
+		samples,
+	})
+}
+
+// ogg_opus wants the rate as a const generic
+fn decode_opus(data: &[u8]) -> Result<(Vec<i16>, u16)> {
+	let data = Cursor::new(data);
+	let dec = match sample_rate() {
+		8000 => ogg_opus::decode::<_, 8000>(data),
+		12000 => ogg_opus::decode::<_, 12000>(data),
+		16000 => ogg_opus::decode::<_, 16000>(data),
+		24000 => ogg_opus::decode::<_, 24000>(data),
+		48000 => ogg_opus::decode::<_, 48000>(data),
+		rate => bail!("Unsupported sample rate {rate}"),
+	};
+	let (data, meta) = dec?;
+	Ok((data, meta.channels))
+}
+
+/// Half width of the interpolation kernel, in input samples (or output samples when downsampling)
+const TAPS: f64 = 16.0;
+
+/// Hann windowed sinc, with the cutoff at the lower of the two Nyquist frequencies
+fn resample(samples: &[i16], channels: u16, from: u32, to: u32) -> Vec<i16> {
+	if from == to || samples.is_empty() {
+		return samples.to_vec();
+	}
+	let channels = channels as usize;
+	let frames = samples.len() / channels;
+	let out_frames = (frames as u64 * to as u64 / from as u64) as usize;
+	let step = from as f64 / to as f64;
+	// Relative to the input rate
+	let cutoff = f64::min(1.0, to as f64 / from as f64);
+	let width = TAPS / cutoff;
+	let kernel = |x: f64| {
+		if x.abs() >= width {
+			return 0.0;
+		}
+		let window = 0.5 + 0.5 * (PI * x / width).cos();
+		let sinc = match x * cutoff {
+			x if x.abs() < 1e-9 => 1.0,
+			x => (PI * x).sin() / (PI * x),
+		};
+		cutoff * sinc * window
+	};
+	let mut out = Vec::with_capacity(out_frames * channels);
+	for i in 0..out_frames {
+		let pos = i as f64 * step;
+		let first = (pos - width).ceil().max(0.0) as usize;
+		let last = ((pos + width).floor() as usize).min(frames - 1);
+		for c in 0..channels {
+			let acc = (first..=last)
+				.map(|j| samples[j * channels + c] as f64 * kernel(pos - j as f64))
+				.sum::<f64>();
+			out.push(acc.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tone(rate: u32, freq: f64, len: usize) -> Vec<i16> {
+		(0..len)
+			.map(|i| (10000.0 * (2.0 * PI * freq * i as f64 / rate as f64).sin()).round() as i16)
+			.collect()
+	}
+
+	fn crossings(samples: impl Iterator<Item = i16>) -> usize {
+		samples
+			.map(|s| s >= 0)
+			.collect::<Vec<_>>()
+			.windows(2)
+			.filter(|w| w[0] != w[1])
+			.count()
+	}
+
+	#[test]
+	fn sniff_content() {
+		let ogg = |codec: &[u8]| [&b"OggS"[..], &[0; 24], codec].concat();
+		assert_eq!(sniff(None, &ogg(b"OpusHead")), Format::Opus);
+		assert_eq!(sniff(None, &ogg(b"\x01vorbis")), Format::Native("ogg"));
+		assert_eq!(sniff(None, &ogg(b"Speex   ")), Format::Other("OGG"));
+		let wav = Wav {
+			rate: 8000,
+			channels: 1,
+			samples: vec![],
+		};
+		assert_eq!(sniff(None, &wav.serialize()), Format::Wav);
+		assert_eq!(sniff(None, b"fLaC\0\0"), Format::Native("flac"));
+		assert_eq!(sniff(None, b"ID3\x04\0"), Format::Native("mp3"));
+		assert_eq!(sniff(None, b"\xff\xfb\x90\x00"), Format::Native("mp3"));
+		assert_eq!(sniff(None, b"\xff\xf1\x50\x80"), Format::Native("aac"));
+		assert_eq!(sniff(None, b"\0\0\0\x20ftypM4A "), Format::Native("m4a"));
+		assert_eq!(sniff(None, b"\x1a\x45\xdf\xa3"), Format::Other("Matroska"));
+	}
+
+	#[test]
+	fn sniff_content_over_mimetype() {
+		assert_eq!(
+			sniff(Some("audio/ogg"), b"fLaC\0\0"),
+			Format::Native("flac")
+		);
+		assert_eq!(sniff(Some("audio/ogg; codecs=opus"), b"??"), Format::Opus);
+		assert_eq!(sniff(Some("audio/mpeg"), b"??"), Format::Native("mp3"));
+		assert_eq!(sniff(Some("audio/mp4"), b"??"), Format::Native("m4a"));
+		assert_eq!(sniff(Some("text/plain"), b"??"), Format::Unknown);
+		assert_eq!(sniff(None, b""), Format::Unknown);
+	}
+
+	#[test]
+	fn native_flac() {
+		let wav = decode_native(include_bytes!("testdata/tone.flac").to_vec(), "flac").unwrap();
+		assert_eq!((wav.rate, wav.channels), (16000, 1));
+		let expected = tone(16000, 440.0, 1600);
+		assert_eq!(wav.samples.len(), expected.len());
+		let off = wav
+			.samples
+			.iter()
+			.zip(&expected)
+			.map(|(a, b)| (a - b).abs())
+			.max();
+		assert!(off <= Some(1), "{off:?}");
+	}
+
+	#[test]
+	fn resample_identity() {
+		let samples = tone(16000, 440.0, 100);
+		assert_eq!(resample(&samples, 1, 16000, 16000), samples);
+		assert!(resample(&[], 2, 8000, 48000).is_empty());
+	}
+
+	#[test]
+	fn resample_keeps_pitch_and_level() {
+		for (from, to) in [(48000, 16000), (8000, 44100), (44100, 48000)] {
+			let input = tone(from, 440.0, from as usize / 2);
+			let output = resample(&input, 1, from, to);
+			assert_eq!(output.len(), to as usize / 2, "{from} -> {to}");
+			// Half a second of 440 Hz crosses zero 440 times
+			let crossings = crossings(output.iter().copied());
+			assert!(
+				(438..=441).contains(&crossings),
+				"{from} -> {to}: {crossings}"
+			);
+			// Away from the edges, where the kernel runs out of input
+			let peak = output[100..output.len() - 100]
+				.iter()
+				.map(|s| s.abs())
+				.max();
+			assert!(
+				(9800..=10200).contains(&peak.unwrap()),
+				"{from} -> {to}: {peak:?}"
+			);
+		}
+	}
+
+	#[test]
+	fn resample_filters_above_nyquist() {
+		// 6 kHz can't be represented at 8 kHz
+		let output = resample(&tone(48000, 6000.0, 4800), 1, 48000, 8000);
+		let peak = output[50..output.len() - 50].iter().map(|s| s.abs()).max();
+		assert!(peak.unwrap() < 500, "{peak:?}");
+	}
+
+	#[test]
+	fn resample_keeps_channels_apart() {
+		let left = tone(16000, 440.0, 1600);
+		let stereo = left.iter().flat_map(|&l| [l, 0]).collect::<Vec<_>>();
+		let output = resample(&stereo, 2, 16000, 48000);
+		assert_eq!(output.len(), stereo.len() * 3);
+		assert!(output.iter().skip(1).step_by(2).all(|&r| r == 0));
+		let crossings = crossings(output.iter().step_by(2).copied());
+		assert!((87..=89).contains(&crossings), "{crossings}");
+	}
+}
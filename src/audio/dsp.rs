@@ -0,0 +1,452 @@
+use anyhow::{ensure, Result};
+use serde::Deserialize;
+use std::{f32::consts::PI, path::Path};
+use tracing::debug;
+
+use super::wav::{self, Wav};
+
+/// One step of the chain between recording and encoding, applied in the order given in config.yaml.
+/// E.g. [dc-removal, high-pass: {cutoff: 100}, noise-suppression: {}, agc: {}, limiter: {}]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum DspStage {
+	/// Removes constant offset, cheap microphones tend to have some
+	DcRemoval,
+	/// Second order Butterworth, against rumble and handling noise
+	HighPass {
+		/// Hz
+		#[serde(default = "default_cutoff")]
+		cutoff: f32,
+	},
+	/// Slowly pulls the speech level towards target, silence is left alone
+	Agc {
+		/// dBFS RMS
+		#[serde(default = "default_target")]
+		target: f32,
+		/// dB
+		#[serde(default = "default_max_gain")]
+		max_gain: f32,
+	},
+	/// Keeps peaks below ceiling (dBFS)
+	Limiter {
+		#[serde(default = "default_ceiling")]
+		ceiling: f32,
+	},
+	/// Spectral subtraction, the noise profile is taken from the quietest parts of the recording
+	NoiseSuppression {
+		/// Maximum attenuation in dB
+		#[serde(default = "default_reduction")]
+		reduction: f32,
+	},
+}
+
+fn default_cutoff() -> f32 {
+	100.0
+}
+fn default_target() -> f32 {
+	-20.0
+}
+fn default_max_gain() -> f32 {
+	20.0
+}
+fn default_ceiling() -> f32 {
+	-1.0
+}
+fn default_reduction() -> f32 {
+	12.0
+}
+
+impl DspStage {
+	/// The rate is only known once the audio backend or the input file is
+	pub(crate) fn check(&self, rate: Option<u32>) -> Result<()> {
+		if let DspStage::HighPass { cutoff } = *self {
+			ensure!(
+				cutoff > 0.0 && cutoff.is_finite(),
+				"high-pass cutoff must be above 0 Hz, not {cutoff}"
+			);
+			if let Some(rate) = rate {
+				ensure!(
+					cutoff < rate as f32 / 2.0,
+					"high-pass cutoff of {cutoff} Hz must be below half the sample rate of {rate} Hz"
+				);
+			}
+		}
+		Ok(())
+	}
+}
+
+fn db(db: f32) -> f32 {
+	10f32.powf(db / 20.0)
+}
+
+/// Runs a mono recording through the chain
+pub(crate) fn process(chain: &[DspStage], pcm: &[i16], rate: u32) -> Vec<i16> {
+	if chain.is_empty() {
+		return pcm.to_vec();
+	}
+	let mut x = pcm
+		.iter()
+		.map(|&s| s as f32 / i16::MAX as f32)
+		.collect::<Vec<_>>();
+	for stage in chain {
+		debug!(?stage, "DSP");
+		match *stage {
+			DspStage::DcRemoval => dc_removal(&mut x),
+			DspStage::HighPass { cutoff } => high_pass(&mut x, cutoff, rate as f32),
+			DspStage::Agc { target, max_gain } => agc(&mut x, target, max_gain, rate as f32),
+			DspStage::Limiter { ceiling } => limiter(&mut x, ceiling, rate as f32),
+			DspStage::NoiseSuppression { reduction } => noise_suppression(&mut x, reduction, rate),
+		}
+	}
+	x.iter()
+		.map(|&s| {
+			(s * i16::MAX as f32)
+				.round()
+				.clamp(i16::MIN as f32, i16::MAX as f32) as i16
+		})
+		.collect()
+}
+
+/// Runs a WAV file through the chain, for tuning the settings without a microphone
+pub(crate) fn process_file(chain: &[DspStage], input: &Path, output: &Path) -> Result<()> {
+	let wav = wav::read(input)?;
+	for stage in chain {
+		stage.check(Some(wav.rate))?;
+	}
+	let channels = wav.channels as usize;
+	let mut samples = wav.samples.clone();
+	for c in 0..channels {
+		let channel = wav
+			.samples
+			.iter()
+			.skip(c)
+			.step_by(channels)
+			.cloned()
+			.collect::<Vec<_>>();
+		let processed = process(chain, &channel, wav.rate);
+		for (i, s) in processed.into_iter().enumerate() {
+			samples[i * channels + c] = s;
+		}
+	}
+	let out = Wav { samples, ..wav };
+	std::fs::write(output, out.serialize())?;
+	Ok(())
+}
+
+fn dc_removal(x: &mut [f32]) {
+	const R: f32 = 0.995;
+	let (mut x1, mut y1) = (0.0, 0.0);
+	for s in x {
+		let y = *s - x1 + R * y1;
+		x1 = *s;
+		y1 = y;
+		*s = y;
+	}
+}
+
+/// RBJ cookbook biquad
+fn high_pass(x: &mut [f32], cutoff: f32, rate: f32) {
+	let w0 = 2.0 * PI * cutoff / rate;
+	let alpha = w0.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+	let cos = w0.cos();
+	let a0 = 1.0 + alpha;
+	let b0 = (1.0 + cos) / 2.0 / a0;
+	let b1 = -(1.0 + cos) / a0;
+	let b2 = b0;
+	let a1 = -2.0 * cos / a0;
+	let a2 = (1.0 - alpha) / a0;
+	let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+	for s in x {
+		let y = b0 * *s + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+		(x2, x1) = (x1, *s);
+		(y2, y1) = (y1, y);
+		*s = y;
+	}
+}
+
+fn agc(x: &mut [f32], target: f32, max_gain: f32, rate: f32) {
+	/// Blocks below this are not speech and don't move the gain
+	const GATE: f32 = -50.0;
+	/// Per block, about 200 ms to settle
+	const SMOOTHING: f32 = 0.05;
+	let block = (rate / 100.0) as usize;
+	let mut gain = 1.0;
+	for chunk in x.chunks_mut(block.max(1)) {
+		let rms = (chunk.iter().map(|s| s * s).sum::<f32>() / chunk.len() as f32).sqrt();
+		let level = 20.0 * rms.max(1e-9).log10();
+		let start = gain;
+		if level > GATE {
+			let wanted = db((target - level).min(max_gain));
+			gain += (wanted - gain) * SMOOTHING;
+		}
+		// Ramp within the block to avoid zipper noise
+		let len = chunk.len() as f32;
+		for (i, s) in chunk.iter_mut().enumerate() {
+			*s *= start + (gain - start) * i as f32 / len;
+		}
+	}
+}
+
+fn limiter(x: &mut [f32], ceiling: f32, rate: f32) {
+	const RELEASE: f32 = 0.05;
+	let ceiling = db(ceiling);
+	let release = (-1.0 / (RELEASE * rate)).exp();
+	let mut env = 0.0f32;
+	for s in x {
+		// Instant attack, so the envelope is never below the current peak
+		env = s.abs().max(env * release);
+		if env > ceiling {
+			*s *= ceiling / env;
+		}
+	}
+}
+
+fn noise_suppression(x: &mut [f32], reduction: f32, rate: u32) {
+	/// Share of frames assumed to be noise only
+	const NOISE_FRAMES: f32 = 0.2;
+	/// Subtract a bit more than the estimate, against musical noise
+	const OVERSUBTRACT: f32 = 1.5;
+	let size = ((rate / 30) as usize).next_power_of_two();
+	let hop = size / 2;
+	if x.len() < size * 4 {
+		return;
+	}
+	// Periodic Hann, sums to one at 50% overlap
+	let window = (0..size)
+		.map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos())
+		.collect::<Vec<_>>();
+	let spectra = (0..=(x.len() - size) / hop)
+		.map(|f| {
+			let mut frame = (0..size)
+				.map(|i| (x[f * hop + i] * window[i], 0.0))
+				.collect::<Vec<_>>();
+			fft(&mut frame, false);
+			frame
+		})
+		.collect::<Vec<_>>();
+	let power = |frame: &[(f32, f32)]| frame.iter().map(|(r, i)| r * r + i * i).collect::<Vec<_>>();
+	let mut by_energy = spectra
+		.iter()
+		.map(|f| power(f).iter().sum::<f32>())
+		.enumerate()
+		.collect::<Vec<_>>();
+	by_energy.sort_by(|a, b| a.1.total_cmp(&b.1));
+	let quiet = ((by_energy.len() as f32 * NOISE_FRAMES) as usize).max(1);
+	let mut noise = vec![0.0; size];
+	for &(f, _) in &by_energy[..quiet] {
+		for (n, p) in noise.iter_mut().zip(power(&spectra[f])) {
+			*n += p / quiet as f32;
+		}
+	}
+	let floor = db(-reduction);
+	let mut out = vec![0.0; x.len()];
+	for (f, mut frame) in spectra.into_iter().enumerate() {
+		for (bin, p) in frame.iter_mut().zip(noise.iter()) {
+			let energy = bin.0 * bin.0 + bin.1 * bin.1;
+			let gain = (1.0 - OVERSUBTRACT * p / energy.max(1e-12))
+				.max(0.0)
+				.sqrt()
+				.max(floor);
+			*bin = (bin.0 * gain, bin.1 * gain);
+		}
+		fft(&mut frame, true);
+		for (i, (r, _)) in frame.into_iter().enumerate() {
+			out[f * hop + i] += r;
+		}
+	}
+	// The first and last half frames only got one window, leave them as they were
+	let covered = (out.len() - size) / hop * hop + size;
+	x[hop..covered - hop].copy_from_slice(&out[hop..covered - hop]);
+}
+
+/// In place radix-2, inverse is scaled by 1/n
+fn fft(x: &mut [(f32, f32)], inverse: bool) {
+	let n = x.len();
+	let mut j = 0;
+	for i in 1..n {
+		let mut bit = n >> 1;
+		while j & bit != 0 {
+			j ^= bit;
+			bit >>= 1;
+		}
+		j |= bit;
+		if i < j {
+			x.swap(i, j);
+		}
+	}
+	let sign = if inverse { 1.0 } else { -1.0 };
+	let mut len = 2;
+	while len <= n {
+		let angle = sign * 2.0 * PI / len as f32;
+		for start in (0..n).step_by(len) {
+			for k in 0..len / 2 {
+				let (wr, wi) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+				let (ar, ai) = x[start + k];
+				let (br, bi) = x[start + k + len / 2];
+				let (tr, ti) = (br * wr - bi * wi, br * wi + bi * wr);
+				x[start + k] = (ar + tr, ai + ti);
+				x[start + k + len / 2] = (ar - tr, ai - ti);
+			}
+		}
+		len <<= 1;
+	}
+	if inverse {
+		for s in x {
+			*s = (s.0 / n as f32, s.1 / n as f32);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const RATE: u32 = 8000;
+
+	fn fixture(wav: &[u8]) -> Vec<i16> {
+		let wav = Wav::parse(wav).unwrap();
+		assert_eq!((wav.rate, wav.channels), (RATE, 1));
+		wav.samples
+	}
+
+	fn float(pcm: &[i16]) -> Vec<f32> {
+		pcm.iter().map(|&s| s as f32 / i16::MAX as f32).collect()
+	}
+
+	/// Of the second half, after the filters have settled
+	fn settled(pcm: &[i16]) -> Vec<f32> {
+		float(&pcm[pcm.len() / 2..])
+	}
+
+	/// Amplitude of one frequency, needs a whole number of periods
+	fn amplitude(x: &[f32], freq: f32) -> f32 {
+		let (re, im) = x.iter().enumerate().fold((0.0, 0.0), |(re, im), (i, s)| {
+			let phase = 2.0 * PI * freq * i as f32 / RATE as f32;
+			(re + s * phase.cos(), im + s * phase.sin())
+		});
+		2.0 * (re * re + im * im).sqrt() / x.len() as f32
+	}
+
+	fn rms_db(x: &[f32]) -> f32 {
+		20.0 * (x.iter().map(|s| s * s).sum::<f32>() / x.len() as f32)
+			.sqrt()
+			.log10()
+	}
+
+	#[test]
+	fn empty_chain_is_identity() {
+		let pcm = fixture(include_bytes!("testdata/rumble.wav"));
+		assert_eq!(process(&[], &pcm, RATE), pcm);
+	}
+
+	#[test]
+	fn dc_removal() {
+		let pcm = fixture(include_bytes!("testdata/rumble.wav"));
+		let before = settled(&pcm);
+		assert!(before.iter().sum::<f32>() / before.len() as f32 > 0.2);
+		let after = settled(&process(&[DspStage::DcRemoval], &pcm, RATE));
+		let mean = after.iter().sum::<f32>() / after.len() as f32;
+		assert!(mean.abs() < 0.01, "{mean}");
+		let tone = amplitude(&after, 1000.0);
+		assert!((tone - 0.1).abs() < 0.005, "{tone}");
+	}
+
+	#[test]
+	fn high_pass() {
+		let pcm = fixture(include_bytes!("testdata/rumble.wav"));
+		assert!((amplitude(&settled(&pcm), 30.0) - 0.3).abs() < 0.01);
+		let after = settled(&process(
+			&[DspStage::HighPass { cutoff: 100.0 }],
+			&pcm,
+			RATE,
+		));
+		let rumble = amplitude(&after, 30.0);
+		assert!(rumble < 0.05, "{rumble}");
+		let tone = amplitude(&after, 1000.0);
+		assert!((tone - 0.1).abs() < 0.005, "{tone}");
+		let cutoff = |cutoff| DspStage::HighPass { cutoff }.check(Some(RATE));
+		assert!(cutoff(3999.0).is_ok());
+		assert!(cutoff(4000.0).is_err());
+		assert!(cutoff(0.0).is_err());
+	}
+
+	#[test]
+	fn agc() {
+		let pcm = fixture(include_bytes!("testdata/quiet.wav"));
+		let before = rms_db(&settled(&pcm));
+		assert!((before + 35.0).abs() < 0.5, "{before}");
+		let agc = |max_gain| DspStage::Agc {
+			target: -20.0,
+			max_gain,
+		};
+		let after = rms_db(&settled(&process(&[agc(20.0)], &pcm, RATE)));
+		assert!((after + 20.0).abs() < 1.0, "{after}");
+		// Not enough gain to get there
+		let after = rms_db(&settled(&process(&[agc(6.0)], &pcm, RATE)));
+		assert!((after + 29.0).abs() < 1.0, "{after}");
+	}
+
+	#[test]
+	fn agc_leaves_silence_alone() {
+		let silence = vec![3; RATE as usize];
+		let stage = DspStage::Agc {
+			target: -20.0,
+			max_gain: 20.0,
+		};
+		assert_eq!(process(&[stage], &silence, RATE), silence);
+	}
+
+	#[test]
+	fn limiter() {
+		let pcm = fixture(include_bytes!("testdata/loud.wav"));
+		let limited = process(&[DspStage::Limiter { ceiling: -6.0 }], &pcm, RATE);
+		let peak = limited.iter().map(|s| s.unsigned_abs()).max().unwrap() as f32 / i16::MAX as f32;
+		assert!(peak <= db(-6.0) + 1e-4, "{peak}");
+		assert!(peak > db(-7.0), "{peak}");
+		// Below the ceiling, nothing happens
+		let limited = process(&[DspStage::Limiter { ceiling: 0.0 }], &pcm, RATE);
+		let off = limited.iter().zip(&pcm).map(|(a, b)| (a - b).abs()).max();
+		assert!(off <= Some(1), "{off:?}");
+	}
+
+	#[test]
+	fn noise_suppression() {
+		let pcm = fixture(include_bytes!("testdata/noisy.wav"));
+		let rate = RATE as usize;
+		// Noise only, then the tone from 0.5 s to 1.5 s
+		let noise = |x: &[f32]| rms_db(&x[rate / 5..rate * 2 / 5]);
+		let tone = |x: &[f32]| amplitude(&x[rate * 3 / 4..rate * 5 / 4], 1000.0);
+		let before = float(&pcm);
+		assert!((noise(&before) + 39.0).abs() < 1.0, "{}", noise(&before));
+		let stage = DspStage::NoiseSuppression { reduction: 12.0 };
+		let after = float(&process(&[stage], &pcm, RATE));
+		// Random peaks in the noise survive partly, but never more than reduction is taken off
+		let reduced = noise(&before) - noise(&after);
+		assert!((5.0..=12.5).contains(&reduced), "{reduced}");
+		assert!((tone(&after) - 0.3).abs() < 0.02, "{}", tone(&after));
+	}
+
+	#[test]
+	fn fft_round_trip() {
+		let n = 64;
+		let x = (0..n)
+			.map(|i| ((2.0 * PI * 5.0 * i as f32 / n as f32).cos(), 0.0))
+			.collect::<Vec<_>>();
+		let mut y = x.clone();
+		fft(&mut y, false);
+		for (bin, (re, im)) in y.iter().enumerate() {
+			let expected = match bin {
+				5 | 59 => n as f32 / 2.0,
+				_ => 0.0,
+			};
+			assert!(
+				(re - expected).abs() < 1e-3 && im.abs() < 1e-3,
+				"{bin}: {re} {im}"
+			);
+		}
+		fft(&mut y, true);
+		for (a, b) in x.iter().zip(&y) {
+			assert!((a.0 - b.0).abs() < 1e-5 && b.1.abs() < 1e-5, "{a:?} {b:?}");
+		}
+	}
+}
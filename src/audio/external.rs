@@ -0,0 +1,53 @@
+use anyhow::{ensure, Context, Result};
+use std::{
+	io::Write,
+	process::{Command, Stdio},
+	thread,
+};
+
+use super::{sample_rate, wav::Wav};
+
+/// Runs the TTS command with the text on stdin, expecting WAV on stdout
+#[tracing::instrument(skip(text))]
+pub(crate) fn speak(cmd: &[String], text: &str) -> Result<Vec<u8>> {
+	let out = filter(cmd, text.as_bytes().to_vec())?;
+	ensure!(!out.is_empty(), "TTS produced no audio");
+	Ok(out)
+}
+
+/// Runs the speech-to-text command with a mono WAV recording on stdin, expecting text on stdout
+#[tracing::instrument(skip(pcm))]
+pub(crate) fn transcribe(cmd: &[String], pcm: &[i16]) -> Result<String> {
+	let wav = Wav {
+		rate: sample_rate(),
+		channels: 1,
+		samples: pcm.to_vec(),
+	};
+	let out = filter(cmd, wav.serialize())?;
+	let text = String::from_utf8(out).context("Transcription isn't UTF-8")?;
+	Ok(text.trim().to_owned())
+}
+
+pub(super) fn filter(cmd: &[String], input: Vec<u8>) -> Result<Vec<u8>> {
+	let (path, args) = cmd.split_first().context("Empty command")?;
+	let mut child = Command::new(path)
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.with_context(|| format!("$ {path}"))?;
+	let mut stdin = child.stdin.take().unwrap();
+	// Feed from a thread, the output pipe might fill up before all input is read
+	let writer = thread::spawn(move || stdin.write_all(&input));
+	let out = child
+		.wait_with_output()
+		.context("Process exit waiting failure")?;
+	writer.join().ok();
+	ensure!(
+		out.status.success(),
+		"{path} failed: {}",
+		String::from_utf8_lossy(&out.stderr)
+	);
+	Ok(out.stdout)
+}
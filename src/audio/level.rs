@@ -0,0 +1,133 @@
+use once_cell::sync::OnceCell;
+use std::borrow::Cow;
+use tracing::debug;
+
+use crate::config::{PlaybackSettings, TimeOfDay};
+
+pub(super) static PLAYBACK: OnceCell<PlaybackSettings> = OnceCell::new();
+
+/// Frames quieter than this (dBFS) don't count towards a message's loudness
+const GATE: f64 = -50.0;
+/// Never boost more than this (dB), whatever the target
+const MAX_BOOST: f64 = 20.0;
+
+fn settings() -> &'static PlaybackSettings {
+	PLAYBACK.get_or_init(PlaybackSettings::default)
+}
+
+fn db(db: f64) -> f64 {
+	10f64.powf(db / 20.0)
+}
+
+fn scale(data: &[i16], factor: f64) -> Vec<i16> {
+	data.iter()
+		.map(|&s| {
+			(s as f64 * factor)
+				.round()
+				.clamp(i16::MIN as f64, i16::MAX as f64) as i16
+		})
+		.collect()
+}
+
+/// Brings a decoded message to the configured loudness, without clipping it
+pub(super) fn normalize(data: Vec<i16>, rate: u32, channels: u16) -> Vec<i16> {
+	let Some(target) = settings().normalize else {
+		return data;
+	};
+	match gain(&data, rate, channels, target) {
+		Some(gain) => scale(&data, gain),
+		None => data,
+	}
+}
+
+/// Factor that gets the message to target (dBFS), None if it's all below the gate
+fn gain(data: &[i16], rate: u32, channels: u16, target: f32) -> Option<f64> {
+	let frame = (rate as usize / 50 * channels as usize).max(1);
+	let (power, frames) = data
+		.chunks(frame)
+		.map(|f| f.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / f.len() as f64)
+		.filter(|&p| 10.0 * (p / (i16::MAX as f64).powi(2)).log10() > GATE)
+		.fold((0.0, 0), |(sum, n), p| (sum + p, n + 1));
+	if frames == 0 {
+		return None;
+	}
+	let level = 10.0 * (power / frames as f64 / (i16::MAX as f64).powi(2)).log10();
+	let peak = data
+		.iter()
+		.map(|s| s.unsigned_abs())
+		.max()
+		.unwrap_or(0)
+		.max(1) as f64;
+	let gain = db((target as f64 - level).min(MAX_BOOST)).min(i16::MAX as f64 / peak);
+	debug!(level, gain, "Normalizing");
+	Some(gain)
+}
+
+/// Device volume in dB, from the first profile that matches the time of day
+fn volume() -> f32 {
+	let now = TimeOfDay::now();
+	let settings = settings();
+	settings
+		.profiles
+		.iter()
+		.find(|p| now.within(p.from, p.to))
+		.map_or(settings.volume, |p| p.volume)
+}
+
+/// Applies the device volume to anything about to be played
+pub(super) fn apply_volume(data: &[i16]) -> Cow<'_, [i16]> {
+	match volume() {
+		v if v == 0.0 => Cow::Borrowed(data),
+		v => Cow::Owned(scale(data, db(v as f64))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const RATE: u32 = 8000;
+
+	/// One second of 500 Hz at amplitude (full scale is 1)
+	fn sine(amplitude: f64) -> Vec<i16> {
+		(0..RATE)
+			.map(|i| {
+				let phase = 2.0 * std::f64::consts::PI * 500.0 * i as f64 / RATE as f64;
+				(amplitude * i16::MAX as f64 * phase.sin()).round() as i16
+			})
+			.collect()
+	}
+
+	fn near(a: f64, b: f64) -> bool {
+		(a - b).abs() < 0.01 * b
+	}
+
+	#[test]
+	fn gain_to_target() {
+		// RMS of a sine is 3 dB below its amplitude
+		let quiet = sine(db(-32.0));
+		let factor = gain(&quiet, RATE, 1, -20.0).unwrap();
+		assert!(near(factor, db(15.0)), "{factor}");
+		let factor = gain(&quiet, RATE, 1, -38.0).unwrap();
+		assert!(near(factor, db(-3.0)), "{factor}");
+	}
+
+	#[test]
+	fn gain_limits() {
+		let factor = gain(&sine(db(-45.0)), RATE, 1, -10.0).unwrap();
+		assert!(near(factor, db(MAX_BOOST)), "{factor}");
+		// Would need 9 dB, the peak only leaves room for 6
+		let factor = gain(&sine(0.5), RATE, 1, 0.0).unwrap();
+		assert!(near(factor, 2.0), "{factor}");
+		assert_eq!(gain(&sine(db(-60.0)), RATE, 1, -20.0), None);
+		assert_eq!(gain(&[], RATE, 1, -20.0), None);
+	}
+
+	#[test]
+	fn scale_clamps() {
+		assert_eq!(
+			scale(&[100, -100, 20000, -20000], 2.0),
+			[200, -200, i16::MAX, i16::MIN]
+		);
+	}
+}
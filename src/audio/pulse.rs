@@ -1,3 +1,4 @@
+use super::{AudioBackend, Playback};
 use crate::status;
 use crate::status::AudioStatus;
 use anyhow::{Context, Result};
@@ -7,11 +8,34 @@ use libpulse_binding::{
 };
 use libpulse_simple_binding::Simple;
 
-use std::ops::ControlFlow;
+use std::{
+	ops::ControlFlow,
+	sync::atomic::{AtomicBool, Ordering},
+};
+
+const SAMPLE_RATE: u32 = 16000;
+
+pub(crate) struct Pulse;
+
+impl AudioBackend for Pulse {
+	fn sample_rate(&self) -> u32 {
+		SAMPLE_RATE
+	}
 
-pub(crate) const SAMPLE_RATE: u32 = 16000;
+	fn record(&self, sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()> {
+		record(sample)
+	}
 
-pub(crate) fn record(mut sample: impl FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()> {
+	fn play(&self, data: &[i16], channels: u16, abort: &AtomicBool) -> Result<Playback> {
+		play(
+			data,
+			channels.try_into().context("Insane channel count")?,
+			abort,
+		)
+	}
+}
+
+fn record(sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()> {
 	let input = Simple::new(
 		None,
 		env!("CARGO_PKG_NAME"),
@@ -37,7 +61,7 @@ pub(crate) fn record(mut sample: impl FnMut(&[u8]) -> Result<ControlFlow<()>>) -
 	Ok(())
 }
 
-pub(crate) fn play(data: &[i16], channels: u8) -> Result<()> {
+fn play(data: &[i16], channels: u8, abort: &AtomicBool) -> Result<Playback> {
 	let spec = Spec {
 		format: Format::S16le,
 		channels,
@@ -57,6 +81,10 @@ pub(crate) fn play(data: &[i16], channels: u8) -> Result<()> {
 	.context("Pulseaudio open")?;
 	let _guard = status::audio(AudioStatus::Playing);
 	for chunk in data.chunks(2048) {
+		if abort.load(Ordering::Relaxed) {
+			output.flush()?;
+			return Ok(Playback::Skipped);
+		}
 		let block = chunk
 			.iter()
 			.flat_map(|i| i.to_ne_bytes())
@@ -64,5 +92,5 @@ pub(crate) fn play(data: &[i16], channels: u8) -> Result<()> {
 		output.write(&block)?;
 	}
 	output.drain()?;
-	Ok(())
+	Ok(Playback::Played)
 }
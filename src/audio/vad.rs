@@ -0,0 +1,90 @@
+use once_cell::sync::OnceCell;
+use std::time::Duration;
+
+use super::sample_rate;
+use crate::config::VadSettings;
+
+pub(super) static VAD: OnceCell<VadSettings> = OnceCell::new();
+
+/// Energy is judged per frame of this length
+const FRAME: Duration = Duration::from_millis(20);
+/// Less speech than this is a click or a bump, not a message
+const MIN_SPEECH: Duration = Duration::from_millis(100);
+/// Hands-free recordings give up if nobody starts talking
+const NO_SPEECH: Duration = Duration::from_secs(5);
+
+fn settings() -> &'static VadSettings {
+	VAD.get_or_init(VadSettings::default)
+}
+
+fn frame_len() -> usize {
+	(FRAME.as_secs_f64() * sample_rate() as f64) as usize
+}
+
+fn frames(d: Duration) -> usize {
+	(d.as_secs_f64() / FRAME.as_secs_f64()).ceil() as usize
+}
+
+fn is_speech(frame: &[i16]) -> bool {
+	let power = frame.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / frame.len() as f64;
+	let dbfs = 10.0 * (power / (i16::MAX as f64).powi(2)).log10();
+	dbfs > settings().threshold as f64
+}
+
+/// Cuts leading and trailing silence, keeping a bit of padding. None if there is no speech at all.
+pub(super) fn trim(pcm: &[i16]) -> Option<&[i16]> {
+	let len = frame_len();
+	let speech = pcm.chunks(len).map(is_speech).collect::<Vec<_>>();
+	if speech.iter().filter(|&&s| s).count() < frames(MIN_SPEECH) {
+		return None;
+	}
+	let first = speech.iter().position(|&s| s)?;
+	let last = speech.iter().rposition(|&s| s)?;
+	let pad = frames(settings().pad);
+	let start = first.saturating_sub(pad) * len;
+	let end = ((last + 1 + pad) * len).min(pcm.len());
+	Some(&pcm[start..end])
+}
+
+/// Tells a running hands-free recording when to stop
+pub(super) struct EndOfSpeech {
+	pending: Vec<i16>,
+	heard: usize,
+	silent: usize,
+	total: usize,
+}
+
+impl EndOfSpeech {
+	pub fn new() -> Self {
+		EndOfSpeech {
+			pending: Vec::new(),
+			heard: 0,
+			silent: 0,
+			total: 0,
+		}
+	}
+
+	/// True once there was speech followed by enough silence, or no speech for too long
+	pub fn push(&mut self, samples: &[i16]) -> bool {
+		let len = frame_len();
+		self.pending.extend_from_slice(samples);
+		let mut done = false;
+		for frame in self.pending.chunks_exact(len) {
+			self.total += 1;
+			match is_speech(frame) {
+				true => {
+					self.heard += 1;
+					self.silent = 0;
+				}
+				false => self.silent += 1,
+			}
+			done |= match self.heard >= frames(MIN_SPEECH) {
+				true => self.silent >= frames(settings().silence),
+				false => self.total >= frames(NO_SPEECH),
+			};
+		}
+		let rest = self.pending.len() / len * len;
+		self.pending.drain(..rest);
+		done
+	}
+}
@@ -0,0 +1,349 @@
+use super::{AudioBackend, Playback};
+use crate::status::{self, AudioStatus};
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+	fs,
+	ops::ControlFlow,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+	thread::sleep,
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+
+/// Minimal RIFF WAVE, integer or float PCM in, 16 bit out
+pub(crate) struct Wav {
+	pub rate: u32,
+	pub channels: u16,
+	pub samples: Vec<i16>,
+}
+
+impl Wav {
+	pub fn parse(data: &[u8]) -> Result<Wav> {
+		ensure!(
+			data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WAVE",
+			"Not a RIFF WAVE file"
+		);
+		let mut fmt = None;
+		let mut rest = &data[12..];
+		while rest.len() >= 8 {
+			let id = &rest[..4];
+			let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+			// Streamed WAVs may have bogus data lengths
+			let body = &rest[8..8 + len.min(rest.len() - 8)];
+			match id {
+				b"fmt " => {
+					ensure!(body.len() >= 16, "fmt chunk too short");
+					let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+					let format = match u16_at(0) {
+						// WAVE_FORMAT_EXTENSIBLE, the actual format is at the start of the sub format GUID
+						0xfffe if body.len() >= 26 => u16_at(24),
+						format => format,
+					};
+					let channels = u16_at(2);
+					let rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+					let bits = u16_at(14);
+					ensure!(
+						matches!((format, bits), (1, 8 | 16 | 24 | 32) | (3, 32)),
+						"Unsupported sample format {format}, {bits} bit"
+					);
+					ensure!(channels > 0, "No channels");
+					fmt = Some((rate, channels, format, bits));
+				}
+				b"data" => {
+					let (rate, channels, format, bits) = fmt.context("data before fmt chunk")?;
+					let samples = body
+						.chunks_exact(bits as usize / 8)
+						.map(|s| match (format, s) {
+							(1, &[s]) => ((s as i16) - 128) << 8,
+							(1, &[a, b]) => i16::from_le_bytes([a, b]),
+							(1, &[_, a, b]) => i16::from_le_bytes([a, b]),
+							(1, &[_, _, a, b]) => i16::from_le_bytes([a, b]),
+							(_, &[a, b, c, d]) => {
+								let f = f32::from_le_bytes([a, b, c, d]);
+								(f.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+							}
+							_ => unreachable!("Checked in fmt"),
+						})
+						.collect();
+					return Ok(Wav {
+						rate,
+						channels,
+						samples,
+					});
+				}
+				_ => (),
+			}
+			let next = 8 + len + (len & 1);
+			if next > rest.len() {
+				break;
+			}
+			rest = &rest[next..];
+		}
+		bail!("No data chunk")
+	}
+
+	pub fn serialize(&self) -> Vec<u8> {
+		let data_len = self.samples.len() as u32 * 2;
+		let mut out = Vec::with_capacity(44 + data_len as usize);
+		out.extend_from_slice(b"RIFF");
+		out.extend_from_slice(&(36 + data_len).to_le_bytes());
+		out.extend_from_slice(b"WAVEfmt ");
+		out.extend_from_slice(&16u32.to_le_bytes());
+		out.extend_from_slice(&1u16.to_le_bytes());
+		out.extend_from_slice(&self.channels.to_le_bytes());
+		out.extend_from_slice(&self.rate.to_le_bytes());
+		out.extend_from_slice(&(self.rate * self.channels as u32 * 2).to_le_bytes());
+		out.extend_from_slice(&(self.channels * 2).to_le_bytes());
+		out.extend_from_slice(&16u16.to_le_bytes());
+		out.extend_from_slice(b"data");
+		out.extend_from_slice(&data_len.to_le_bytes());
+		for s in &self.samples {
+			out.extend_from_slice(&s.to_le_bytes());
+		}
+		out
+	}
+}
+
+/// Reads recordings from `in/*.wav` (in name order, renamed to `*.wav.done` when picked up)
+/// and writes playback to `out/`.
+/// A recording picks up the next input file when it starts,
+/// and delivers silence in real time once the file is exhausted.
+/// The loop tape only ever records silence, so it doesn't eat the input files.
+/// Playback takes as long as the audio would, and an aborted one writes what was played so far.
+/// Samples per block, for recording and for checking abort during playback
+const BLOCK_LEN: usize = 1024;
+
+#[derive(Debug)]
+pub(crate) struct WavDir {
+	input: PathBuf,
+	output: PathBuf,
+	rate: u32,
+	played: AtomicUsize,
+}
+
+impl WavDir {
+	pub fn new(dir: PathBuf, rate: u32) -> Result<Self> {
+		let input = dir.join("in");
+		let output = dir.join("out");
+		fs::create_dir_all(&input).context("Create WAV input dir")?;
+		fs::create_dir_all(&output).context("Create WAV output dir")?;
+		Ok(WavDir {
+			input,
+			output,
+			rate,
+			played: AtomicUsize::new(0),
+		})
+	}
+
+	fn next_input(&self) -> Result<Option<Vec<i16>>> {
+		let next = fs::read_dir(&self.input)
+			.context("List WAV input dir")?
+			.filter_map(|e| e.ok())
+			.map(|e| e.path())
+			.filter(|p| p.extension().map_or(false, |ext| ext == "wav"))
+			.min();
+		let next = match next {
+			Some(next) => next,
+			None => return Ok(None),
+		};
+		let wav = read(&next)?;
+		fs::rename(&next, next.with_extension("wav.done")).context("Mark WAV input done")?;
+		ensure!(
+			wav.rate == self.rate,
+			"{next:?} has sample rate {}, expected {}",
+			wav.rate,
+			self.rate
+		);
+		debug!(?next, samples = wav.samples.len(), "recording from file");
+		// Downmix to mono
+		let channels = wav.channels as usize;
+		Ok(Some(
+			wav.samples
+				.chunks(channels)
+				.map(|c| (c.iter().map(|&s| s as i32).sum::<i32>() / c.len() as i32) as i16)
+				.collect(),
+		))
+	}
+
+	/// Delivers input in real time, then silence until sample breaks
+	fn feed(
+		&self,
+		input: &[i16],
+		sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>,
+	) -> Result<()> {
+		let block_time = Duration::from_secs_f64(BLOCK_LEN as f64 / self.rate as f64);
+		let mut next = Instant::now();
+		let mut pos = 0;
+		loop {
+			let block = (pos..pos + BLOCK_LEN)
+				.map(|i| input.get(i).copied().unwrap_or(0))
+				.flat_map(|s| s.to_le_bytes())
+				.collect::<Vec<u8>>();
+			pos += BLOCK_LEN;
+			next += block_time;
+			sleep(next.saturating_duration_since(Instant::now()));
+			if sample(&block)?.is_break() {
+				return Ok(());
+			}
+		}
+	}
+}
+
+impl AudioBackend for WavDir {
+	fn sample_rate(&self) -> u32 {
+		self.rate
+	}
+
+	#[tracing::instrument(skip(sample))]
+	fn record(&self, sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>) -> Result<()> {
+		let input = self.next_input()?.unwrap_or_default();
+		self.feed(&input, sample)
+	}
+
+	#[tracing::instrument(skip(sample))]
+	fn record_continuous(
+		&self,
+		sample: &mut dyn FnMut(&[u8]) -> Result<ControlFlow<()>>,
+	) -> Result<()> {
+		self.feed(&[], sample)
+	}
+
+	#[tracing::instrument(skip(self, data, abort))]
+	fn play(&self, data: &[i16], channels: u16, abort: &AtomicBool) -> Result<Playback> {
+		let _guard = status::audio(AudioStatus::Playing);
+		let block_len = BLOCK_LEN * channels.max(1) as usize;
+		let start = Instant::now();
+		let mut played = 0;
+		let mut result = Playback::Played;
+		for block in data.chunks(block_len) {
+			if abort.load(Ordering::Relaxed) {
+				result = Playback::Skipped;
+				break;
+			}
+			played += block.len();
+			let frames = played / channels.max(1) as usize;
+			let due = start + Duration::from_secs_f64(frames as f64 / self.rate as f64);
+			sleep(due.saturating_duration_since(Instant::now()));
+		}
+		let ts = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_millis();
+		let no = self.played.fetch_add(1, Ordering::Relaxed);
+		let path = self.output.join(format!("{ts}-{no}.wav"));
+		let wav = Wav {
+			rate: self.rate,
+			channels,
+			samples: data[..played].to_vec(),
+		};
+		fs::write(&path, wav.serialize()).with_context(|| format!("Write {path:?}"))?;
+		debug!(?path, ?result, "played to file");
+		Ok(result)
+	}
+}
+
+pub(crate) fn read(path: &Path) -> Result<Wav> {
+	Wav::parse(&fs::read(path).with_context(|| format!("Read {path:?}"))?)
+		.with_context(|| format!("Parse {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let wav = Wav {
+			rate: 16000,
+			channels: 2,
+			samples: vec![0, 1, -1, i16::MAX, i16::MIN, 1234, -4321, 7],
+		};
+		let parsed = Wav::parse(&wav.serialize()).unwrap();
+		assert_eq!(parsed.rate, wav.rate);
+		assert_eq!(parsed.channels, wav.channels);
+		assert_eq!(parsed.samples, wav.samples);
+	}
+
+	#[test]
+	fn float_and_8bit() {
+		let mut wav = Wav {
+			rate: 8000,
+			channels: 1,
+			samples: vec![],
+		}
+		.serialize();
+		// Rewrite the header to 32 bit float
+		wav[20..22].copy_from_slice(&3u16.to_le_bytes());
+		wav[34..36].copy_from_slice(&32u16.to_le_bytes());
+		wav.extend_from_slice(&[0.5f32, -1.0, 2.0].map(f32::to_le_bytes).concat());
+		wav[40..44].copy_from_slice(&12u32.to_le_bytes());
+		assert_eq!(
+			Wav::parse(&wav).unwrap().samples,
+			[i16::MAX / 2, -i16::MAX, i16::MAX]
+		);
+
+		wav.truncate(44);
+		wav[20..22].copy_from_slice(&1u16.to_le_bytes());
+		wav[34..36].copy_from_slice(&8u16.to_le_bytes());
+		wav.extend_from_slice(&[128, 0, 255]);
+		wav[40..44].copy_from_slice(&3u32.to_le_bytes());
+		assert_eq!(Wav::parse(&wav).unwrap().samples, [0, i16::MIN, 127 << 8]);
+	}
+
+	#[test]
+	fn garbage() {
+		assert!(Wav::parse(b"RIFF\0\0\0\0WAVE").is_err());
+		assert!(Wav::parse(b"OggS").is_err());
+	}
+
+	#[test]
+	fn wav_dir_round_trip() {
+		crate::status::init_for_tests();
+		let dir = std::env::temp_dir().join(format!("gegensprech-wavdir-{}", std::process::id()));
+		let backend = WavDir::new(dir.clone(), 8000).unwrap();
+		let samples = (0..3000)
+			.map(|i| (i * 7 % 2000 - 1000) as i16)
+			.collect::<Vec<_>>();
+		let input = Wav {
+			rate: 8000,
+			channels: 1,
+			samples: samples.clone(),
+		};
+		fs::write(dir.join("in/a.wav"), input.serialize()).unwrap();
+		fs::write(dir.join("in/b.wav"), input.serialize()).unwrap();
+
+		let mut recorded = Vec::new();
+		let mut sample = |block: &[u8]| {
+			recorded.extend(block.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])));
+			Ok(match recorded.len() >= samples.len() {
+				true => ControlFlow::Break(()),
+				false => ControlFlow::Continue(()),
+			})
+		};
+		backend.record(&mut sample).unwrap();
+		assert_eq!(recorded[..samples.len()], samples);
+		assert!(dir.join("in/a.wav.done").exists());
+
+		// The loop tape leaves the next input alone
+		backend
+			.record_continuous(&mut |_| Ok(ControlFlow::Break(())))
+			.unwrap();
+		assert!(dir.join("in/b.wav").exists());
+
+		let played = backend
+			.play(&recorded[..samples.len()], 1, &AtomicBool::new(false))
+			.unwrap();
+		assert_eq!(played, Playback::Played);
+		let out = fs::read_dir(dir.join("out"))
+			.unwrap()
+			.map(|e| e.unwrap().path())
+			.collect::<Vec<_>>();
+		assert_eq!(out.len(), 1);
+		assert_eq!(read(&out[0]).unwrap().samples, samples);
+
+		let aborted = backend.play(&samples, 1, &AtomicBool::new(true)).unwrap();
+		assert_eq!(aborted, Playback::Skipped);
+		fs::remove_dir_all(&dir).ok();
+	}
+}
@@ -1,21 +1,26 @@
 use anyhow::Result;
-use rppal::gpio::Gpio;
-use rppal::gpio::InputPin;
 use rppal::gpio::Level;
-use rppal::gpio::Pin;
-use rppal::gpio::Trigger;
-use rppal::system::DeviceInfo;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::sync::mpsc::Sender;
 use tracing::debug;
+use tracing::error;
+use tracing::info;
 use tracing::trace;
+use tracing::warn;
 
 use crate::audio;
+use crate::cmd::Exclusive;
 use crate::cmd::Morse;
 use crate::cmd::MorseWord;
+use crate::config::Calibration;
+use crate::config::MorseSettings;
+use crate::hw::Edges;
+use crate::keyer::{Dot, Keyed, Keyer};
+use crate::mtx::Outgoing;
+use crate::status::{self, Flash};
 
 #[derive(Debug, PartialEq, Eq)]
 enum PinPoll {
@@ -24,57 +29,58 @@ enum PinPoll {
 }
 #[derive(Debug, PartialEq, Eq)]
 enum Press {
-	Short(Instant),
+	Short(Instant, Instant),
 	LongStart(Instant),
 	LongEnd(Instant, Instant),
 }
 
-struct EdgeRaw {
-	pin: InputPin,
-}
 struct EdgeDeb {
-	raw: EdgeRaw,
+	raw: Box<dyn Edges>,
 	lrs: Level,
 	deb: Duration,
 }
 struct Button {
 	edge: EdgeDeb,
 	lpd: Duration,
+	word_timeout: Duration,
+	/// Only in adaptive mode
+	pace: Option<Pace>,
+	sidetone: bool,
 	longdown: Option<(bool, Instant)>,
 }
 
-impl EdgeRaw {
-	fn new(pin: Pin) -> Result<Self> {
-		let mut pin = pin.into_input_pullup();
-		pin.set_interrupt(Trigger::Both, None)?;
-		Ok(Self { pin })
-	}
-	fn current(&self) -> Level {
-		self.pin.read()
-	}
-	#[tracing::instrument(skip(self))]
-	fn next(&mut self, timeout: Option<Instant>) -> Result<bool> {
-		let ret = match timeout {
-			Some(timeout) => match timeout.checked_duration_since(Instant::now()) {
-				sleep @ Some(_) => self.pin.poll_interrupt(false, sleep)?,
-				None => None,
-			},
-			None => self.pin.poll_interrupt(false, None)?,
+/// The long press threshold sits between the recent dot and dash lengths
+struct Pace {
+	dot: Dot,
+	/// Word timeout per long press threshold, kept while adapting
+	word_ratio: f32,
+}
+
+/// Adaptive timing stays within these
+const LPD_MIN: Duration = Duration::from_millis(100);
+const LPD_MAX: Duration = Duration::from_secs(1);
+/// Presses per kind during calibration
+const CALIBRATION_PRESSES: usize = 5;
+/// Calibration gives up after a pause this long
+const CALIBRATION_IDLE: Duration = Duration::from_secs(10);
+
+impl Pace {
+	fn new(lpd: Duration, word_timeout: Duration) -> Self {
+		Pace {
+			dot: Dot::new(lpd / 2),
+			word_ratio: word_timeout.as_secs_f32() / lpd.as_secs_f32(),
 		}
-		.is_some();
-		trace!(button_edge = ?ret);
-		Ok(ret)
 	}
 }
+
 impl EdgeDeb {
-	fn new(pin: Pin) -> Result<Self> {
-		let raw = EdgeRaw::new(pin)?;
+	fn new(raw: Box<dyn Edges>) -> Self {
 		let lrs = Level::High;
-		Ok(Self {
+		Self {
 			raw,
 			lrs,
 			deb: Duration::from_millis(10),
-		})
+		}
 	}
 	#[tracing::instrument(skip(self))]
 	fn next(&mut self, timeout: Option<Instant>) -> Result<(PinPoll, Level, Instant)> {
@@ -106,13 +112,53 @@ impl EdgeDeb {
 	}
 }
 impl Button {
-	fn new(edge: EdgeDeb) -> Self {
-		Self {
+	fn new(edge: EdgeDeb, timing: &MorseSettings) -> Self {
+		let mut button = Self {
 			edge,
-			lpd: Duration::from_millis(250),
+			lpd: Duration::ZERO,
+			word_timeout: Duration::ZERO,
+			pace: None,
+			sidetone: timing.sidetone,
 			longdown: None,
+		};
+		button.set_timing(timing.long_press, timing.word_timeout, timing.adaptive);
+		button
+	}
+	fn set_timing(&mut self, lpd: Duration, word_timeout: Duration, adaptive: bool) {
+		self.lpd = lpd;
+		self.word_timeout = word_timeout;
+		self.pace = adaptive.then(|| Pace::new(lpd, word_timeout));
+		info!(?lpd, ?word_timeout, adaptive, "Button timing");
+	}
+	fn feedback(&self, flash: Flash) {
+		status::flash(flash);
+		if self.sidetone {
+			audio::cue(flash);
+		}
+	}
+	/// Moves the thresholds towards a press of a command word
+	fn adapt(&mut self, press: Duration, long: bool) {
+		if let Some(mut dot) = self.pace.as_ref().map(|pace| pace.dot) {
+			dot.update(
+				press,
+				match long {
+					true => Morse::Long,
+					false => Morse::Short,
+				},
+			);
+			self.paced(dot);
 		}
 	}
+	/// Takes over a new dot estimate, only in adaptive mode
+	fn paced(&mut self, dot: Dot) {
+		let Some(pace) = &mut self.pace else {
+			return;
+		};
+		pace.dot = dot;
+		self.lpd = dot.threshold().clamp(LPD_MIN, LPD_MAX);
+		self.word_timeout = self.lpd.mul_f32(pace.word_ratio);
+		trace!(?dot, ?self.lpd, ?self.word_timeout, "Adapted");
+	}
 	#[tracing::instrument(skip(self))]
 	fn next(&mut self, timeout: Option<Instant>) -> Result<Option<Press>> {
 		loop {
@@ -123,7 +169,7 @@ impl Button {
 					return Ok(Some(Press::LongStart(down)));
 				}
 				Some((false, down)) => {
-					let (edge, level, _time) = self.edge.next(Some(down + self.lpd))?;
+					let (edge, level, time) = self.edge.next(Some(down + self.lpd))?;
 					trace!(?edge, ?level, "still short");
 					match edge {
 						PinPoll::Timeout => {
@@ -133,7 +179,7 @@ impl Button {
 						PinPoll::Edge => {
 							assert!(level == Level::High);
 							self.longdown = None;
-							return Ok(Some(Press::Short(down)));
+							return Ok(Some(Press::Short(down, time)));
 						}
 					}
 				}
@@ -161,39 +207,80 @@ impl Button {
 	}
 }
 
-#[tracing::instrument(skip(messages, cmds, running))]
+#[tracing::instrument(skip(button, messages, cmds, running))]
 pub async fn read(
-	button: u8,
-	messages: Sender<audio::Rec>,
-	cmds: crate::cmd::ButtonCommands,
+	button: Box<dyn Edges>,
+	timing: MorseSettings,
+	messages: Sender<Outgoing>,
+	cmds: Arc<crate::cmd::ButtonCommands>,
 	running: Arc<Mutex<Option<crate::cmd::Running>>>,
-	gpio: &Gpio,
 ) -> Result<()> {
-	tracing::info!(raspi=?DeviceInfo::new());
-	let mut button = Button::new(EdgeDeb::new(gpio.get(button)?)?);
+	let mut button = Button::new(EdgeDeb::new(button), &timing);
 	let rt_handle = tokio::runtime::Handle::current();
 	tokio::task::spawn_blocking(move || -> Result<()> {
 		loop {
 			let et = button.next(None)?;
 			trace!(?et);
 			let mut running = running.lock().unwrap();
-			if let Some(running) = running.take() {
-				rt_handle.block_on(running.terminate());
-			}
+			let interrupted = match running.take() {
+				Some(running) => {
+					let playing = running.playing();
+					rt_handle.block_on(running.terminate());
+					playing
+				}
+				None => false,
+			};
 			match et {
-				Some(Press::Short(_)) => {
+				Some(Press::Short(down, up)) => {
+					button.adapt(up - down, false);
+					button.feedback(Flash::Dot);
 					let code = parse_morse(&mut button)?;
+					let known = cmds.knows(&code);
+					// A plain tap skips what's playing, or else plays one held back message
+					// (do not disturb or on-demand playback). Neither is an unknown command.
+					let tap = code.0 == [Morse::Short] && !known;
+					if tap && (interrupted || crate::dnd::release()) {
+						continue;
+					}
+					button.feedback(match known {
+						true => Flash::Found,
+						false => Flash::Unknown,
+					});
+					if let Some(exclusive) = cmds.exclusive(&code) {
+						drop(running);
+						match exclusive {
+							Exclusive::Keyer { end } => {
+								let room = cmds.take_target();
+								if let Some(body) = keyer(&mut button, end)? {
+									messages.blocking_send(Outgoing::Text { body, room }).ok();
+								}
+							}
+							Exclusive::Calibrate => {
+								if let Some(calibration) = calibrate(&mut button)? {
+									let Calibration {
+										long_press,
+										word_timeout,
+									} = calibration;
+									button.set_timing(long_press, word_timeout, timing.adaptive);
+									if let Err(e) = calibration.save(cmds.dir()) {
+										error!("Calibration not kept: {e:#}");
+									}
+								}
+							}
+						}
+						continue;
+					}
 					*running = cmds.exec(code, &messages);
 				}
 				Some(Press::LongStart(_)) => {
 					drop(running);
-					let recording = audio::RecProc::start();
+					let room = cmds.take_target();
+					let recording = audio::RecProc::start(false, room, messages.clone());
 					tracing::debug!("send");
-					let et = button.next(Some(Instant::now() + Duration::from_secs(20)));
+					// The recording enforces its length limit, just wait for the release
+					let et = button.next(None);
 					trace!(?et, "recording, waiting for LongEnd");
-					rt_handle.block_on(async {
-						anyhow::Ok(messages.send(recording.finish().await?).await?)
-					})?;
+					rt_handle.block_on(recording.finish())?;
 				}
 				_ => unreachable!("Waiting for button down, got something else"),
 			};
@@ -203,25 +290,144 @@ pub async fn read(
 	Ok(())
 }
 
+/// Types text until the end word, None if the user gave up or keyed nothing
+#[tracing::instrument(skip(button))]
+fn keyer(button: &mut Button, end: MorseWord) -> Result<Option<String>> {
+	info!("Keyer started");
+	let dot = match &button.pace {
+		Some(pace) => pace.dot,
+		None => Dot::new(button.lpd / 2),
+	};
+	let mut keyer = Keyer::new(dot, end);
+	let mut down = None;
+	loop {
+		// A held button doesn't end letters or words
+		let timeout = match down {
+			Some(_) => None,
+			None => Some(keyer.deadline()),
+		};
+		let (edge, level, time) = button.edge.next(timeout)?;
+		let keyed = match (edge, level) {
+			(PinPoll::Timeout, _) => keyer.idle(Instant::now()),
+			(PinPoll::Edge, Level::Low) => {
+				down = Some(time);
+				keyer.down(time)
+			}
+			(PinPoll::Edge, Level::High) => {
+				if let Some(down) = down.take() {
+					button.feedback(match keyer.up(down, time) {
+						Morse::Short => Flash::Dot,
+						Morse::Long => Flash::Dash,
+					});
+				}
+				Keyed::Going
+			}
+		};
+		let text = match keyed {
+			Keyed::Going => continue,
+			Keyed::Send(text) => {
+				info!(%text, "Keyed");
+				button.feedback(Flash::Found);
+				Some(text).filter(|t| !t.is_empty())
+			}
+			Keyed::GaveUp => {
+				info!("Keyer idle, dropping text");
+				button.feedback(Flash::Unknown);
+				None
+			}
+		};
+		if down.is_some() {
+			// Don't leave the release for the command parser
+			button.edge.next(None)?;
+		}
+		button.paced(keyer.dot());
+		return Ok(text);
+	}
+}
+
+/// Five dots, then five dashes. None if the user gave up or the two can't be told apart.
+#[tracing::instrument(skip(button))]
+fn calibrate(button: &mut Button) -> Result<Option<Calibration>> {
+	info!("Calibrating, press {CALIBRATION_PRESSES} times short, then {CALIBRATION_PRESSES} times long");
+	let mut presses = Vec::new();
+	let mut down = None;
+	while presses.len() < 2 * CALIBRATION_PRESSES {
+		let timeout = match down {
+			Some(_) => None,
+			None => Some(Instant::now() + CALIBRATION_IDLE),
+		};
+		let (edge, level, time) = button.edge.next(timeout)?;
+		match (edge, level) {
+			(PinPoll::Timeout, _) => {
+				info!(presses = presses.len(), "Calibration abandoned");
+				button.feedback(Flash::Unknown);
+				return Ok(None);
+			}
+			(PinPoll::Edge, Level::Low) => down = Some(time),
+			(PinPoll::Edge, Level::High) => {
+				if let Some(down) = down.take() {
+					presses.push(time - down);
+					button.feedback(match presses.len() <= CALIBRATION_PRESSES {
+						true => Flash::Dot,
+						false => Flash::Dash,
+					});
+				}
+			}
+		}
+	}
+	let median = |presses: &mut [Duration]| {
+		presses.sort();
+		presses[presses.len() / 2]
+	};
+	let (dots, dashes) = presses.split_at_mut(CALIBRATION_PRESSES);
+	let (dot, dash) = (median(dots), median(dashes));
+	if dash < dot * 3 / 2 {
+		warn!(
+			?dot,
+			?dash,
+			"Calibration: long presses aren't much longer than short ones, keeping the old timing"
+		);
+		button.feedback(Flash::Unknown);
+		return Ok(None);
+	}
+	// Words end after a pause of seven dots, as in Morse code
+	let calibration = Calibration {
+		long_press: (dot + dash) / 2,
+		word_timeout: (dot * 7).clamp(Duration::from_millis(500), Duration::from_secs(10)),
+	};
+	if let Err(error) = calibration.check() {
+		warn!(?dot, ?dash, "{error:#}, keeping the old timing");
+		button.feedback(Flash::Unknown);
+		return Ok(None);
+	}
+	info!(?dot, ?dash, ?calibration, "Calibrated");
+	button.feedback(Flash::Found);
+	Ok(Some(calibration))
+}
+
 #[tracing::instrument(skip(button))]
 fn parse_morse(button: &mut Button) -> Result<MorseWord> {
 	let mut morse = vec![Morse::Short];
 	let mut timeout = true;
 	loop {
 		let et = button.next(match timeout {
-			true => Some(Instant::now() + Duration::from_secs(2)),
+			true => Some(Instant::now() + button.word_timeout),
 			false => None,
 		})?;
 		morse.push(match et {
-			Some(Press::Short(_)) => {
+			Some(Press::Short(down, up)) => {
+				button.adapt(up - down, false);
+				button.feedback(Flash::Dot);
 				timeout = true;
 				Morse::Short
 			}
 			Some(Press::LongStart(_)) => {
+				button.feedback(Flash::Dash);
 				timeout = false;
 				Morse::Long
 			}
-			Some(Press::LongEnd(_, _)) => {
+			Some(Press::LongEnd(down, up)) => {
+				button.adapt(up - down, true);
 				timeout = true;
 				continue;
 			}
@@ -232,3 +438,67 @@ fn parse_morse(button: &mut Button) -> Result<MorseWord> {
 	debug!(?morse, "Morsed command");
 	Ok(morse)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hw::{scripted::Scripted, Backend};
+
+	fn button(script: &[(u64, Level)]) -> Button {
+		crate::status::init_for_tests();
+		let steps = script
+			.iter()
+			.map(|&(ms, level)| (Duration::from_millis(ms), level));
+		let edges = Scripted::new(steps).button().unwrap().unwrap();
+		let timing = MorseSettings {
+			word_timeout: Duration::from_millis(300),
+			..Default::default()
+		};
+		Button::new(EdgeDeb::new(edges), &timing)
+	}
+
+	#[test]
+	fn morse_word() {
+		use Level::*;
+		let mut button = button(&[
+			(50, Low),
+			(80, High),
+			(100, Low),
+			(400, High),
+			(100, Low),
+			(80, High),
+		]);
+		assert!(matches!(button.next(None).unwrap(), Some(Press::Short(..))));
+		let word = parse_morse(&mut button).unwrap();
+		assert_eq!(word.0, [Morse::Short, Morse::Long, Morse::Short]);
+	}
+
+	#[test]
+	fn long_press() {
+		use Level::*;
+		let mut button = button(&[(50, Low), (600, High)]);
+		let start = match button.next(None).unwrap() {
+			Some(Press::LongStart(start)) => start,
+			other => panic!("Expected a long press, got {other:?}"),
+		};
+		match button.next(None).unwrap() {
+			Some(Press::LongEnd(from, to)) => {
+				assert_eq!(from, start);
+				assert!(to - from >= Duration::from_millis(500));
+			}
+			other => panic!("Expected the end of the long press, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn adaptive_slows_down() {
+		let mut button = button(&[]);
+		button.set_timing(Duration::from_millis(250), Duration::from_secs(2), true);
+		for _ in 0..20 {
+			button.adapt(Duration::from_millis(300), false);
+			button.adapt(Duration::from_millis(900), true);
+		}
+		assert!(button.lpd > Duration::from_millis(500), "{:?}", button.lpd);
+		assert!(button.word_timeout > Duration::from_secs(4));
+	}
+}
@@ -1,4 +1,9 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use futures::{
+	future::{try_join_all, BoxFuture},
+	FutureExt,
+};
+use matrix_sdk::ruma::OwnedRoomId;
 use serde::Deserialize;
 use signal_child::Signalable;
 use std::{
@@ -6,18 +11,39 @@ use std::{
 	collections::HashMap,
 	fmt::Debug,
 	fs::read,
-	path::Path,
+	path::{Path, PathBuf},
 	process::{self, Child, Stdio},
-	time::Duration,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+	signal::unix::{signal, SignalKind},
+	sync::mpsc::Sender,
+	task::JoinHandle,
+	time::sleep,
 };
-use tokio::{sync::mpsc::Sender, task::JoinHandle, time::sleep};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 
-use crate::audio;
+use crate::{
+	audio,
+	config::deser_humantime,
+	mtx::Outgoing,
+	queue::Queue,
+	status::{self, LedPattern},
+};
 
-static MORSE_CMDS: &str = "cmds.yaml";
+pub(crate) static MORSE_CMDS: &str = "cmds.yaml";
+/// How long a room chosen by Morse prefix waits for the recording
+const TARGET_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the cmd file is checked for changes
+const RELOAD_POLL: Duration = Duration::from_secs(5);
+/// How often waiting steps check whether they were aborted
+const STEP_POLL: Duration = Duration::from_millis(100);
 
-#[derive(Deserialize, Debug, Hash, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum Morse {
 	Long,
 	Short,
@@ -34,7 +60,7 @@ impl TryFrom<char> for Morse {
 	}
 }
 
-#[derive(Deserialize, PartialEq, Eq, Hash)]
+#[derive(Deserialize, Clone, PartialEq, Eq, Hash)]
 #[serde(try_from = "Cow<str>")]
 pub struct MorseWord(pub Vec<Morse>);
 
@@ -67,10 +93,10 @@ impl Debug for MorseWord {
 	}
 }
 
-type Cmds = HashMap<MorseWord, Command>;
+pub(crate) type Cmds = HashMap<MorseWord, Command>;
 
 #[derive(Deserialize, Debug)]
-enum Command {
+pub(crate) enum Command {
 	SubProcess {
 		cmd: Vec<String>,
 	},
@@ -82,30 +108,198 @@ enum Command {
 		#[serde(default)]
 		send: bool,
 	},
+	/// Send the next recording to this room instead of the default one
+	Room {
+		room: OwnedRoomId,
+	},
+	/// Start recording right away, stop when the speaker goes quiet (or on the next press)
+	HandsFree,
+	/// Toggle do not disturb
+	Dnd,
+	/// Play the last few incoming messages again
+	ReplayReceived {
+		#[serde(default = "one")]
+		count: usize,
+	},
+	/// Play our own last message
+	ReplaySent,
+	/// Run steps one after another
+	Sequence {
+		steps: Vec<Step>,
+	},
+	/// Run steps at the same time, done when all are
+	Parallel {
+		steps: Vec<Step>,
+	},
+	/// Send a prepared message, e.g. {text: "Coming!"} or {file: dinner.ogg}
+	SendCanned(Canned),
+	/// Type a text message in Morse code, sent when the end word is keyed.
+	/// Eight dots take back the last word.
+	/// The default end word AR (.-.-.) is also the code for +, which can't be typed then.
+	Keyer {
+		#[serde(default = "end_of_message")]
+		end: MorseWord,
+	},
+	/// Measure the button timing: five short presses, then five long ones.
+	/// Kept in calibration.yaml.
+	Calibrate,
+}
+
+/// Commands that read the button themselves
+pub(crate) enum Exclusive {
+	Keyer { end: MorseWord },
+	Calibrate,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+pub(crate) enum Canned {
+	Text {
+		text: String,
+	},
+	/// Relative to the config dir
+	File {
+		file: PathBuf,
+	},
+}
+
+/// Building block for Sequence and Parallel. Files are relative to the config dir.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) enum Step {
+	Sequence {
+		steps: Vec<Step>,
+	},
+	Parallel {
+		steps: Vec<Step>,
+	},
+	Delay {
+		#[serde(deserialize_with = "deser_humantime")]
+		time: Duration,
+	},
+	PlaySound {
+		file: PathBuf,
+	},
+	/// To the room chosen by Morse prefix, or the default one
+	SendText {
+		text: String,
+	},
+	SendAudio {
+		file: PathBuf,
+	},
+	/// Overrides the status LEDs for a while, here and on the other devices
+	/// in the room chosen by Morse prefix, or the default one
+	Led {
+		pattern: LedPattern,
+		#[serde(deserialize_with = "deser_humantime")]
+		time: Duration,
+	},
+}
+
+impl Step {
+	/// Files this step and its children need
+	pub(crate) fn files(&self) -> Vec<&Path> {
+		match self {
+			Step::Sequence { steps } | Step::Parallel { steps } => {
+				steps.iter().flat_map(Step::files).collect()
+			}
+			Step::PlaySound { file } | Step::SendAudio { file } => vec![file],
+			Step::Delay { .. } | Step::SendText { .. } | Step::Led { .. } => vec![],
+		}
+	}
+}
+
+impl Command {
+	pub(crate) fn subprocess(&self) -> Option<&[String]> {
+		match self {
+			Command::SubProcess { cmd } => Some(cmd),
+			_ => None,
+		}
+	}
+
+	pub(crate) fn room(&self) -> Option<&OwnedRoomId> {
+		match self {
+			Command::Room { room } => Some(room),
+			_ => None,
+		}
+	}
+
+	/// What Sequence, Parallel and SendCanned run
+	pub(crate) fn step(&self) -> Option<Step> {
+		match self {
+			Command::Sequence { steps } => Some(Step::Sequence {
+				steps: steps.clone(),
+			}),
+			Command::Parallel { steps } => Some(Step::Parallel {
+				steps: steps.clone(),
+			}),
+			Command::SendCanned(Canned::Text { text }) => {
+				Some(Step::SendText { text: text.clone() })
+			}
+			Command::SendCanned(Canned::File { file }) => {
+				Some(Step::SendAudio { file: file.clone() })
+			}
+			_ => None,
+		}
+	}
 }
 
 fn ftrue() -> bool {
 	true
 }
 
-fn deser_humantime<'de, D: serde::Deserializer<'de>>(de: D) -> Result<Duration, D::Error> {
-	Ok(String::deserialize(de)?
-		.parse::<humantime::Duration>()
-		.map_err(serde::de::Error::custom)?
-		.into())
+fn one() -> usize {
+	1
+}
+
+/// The AR prosign, shares its code with +
+fn end_of_message() -> MorseWord {
+	MorseWord(vec![
+		Morse::Short,
+		Morse::Long,
+		Morse::Short,
+		Morse::Long,
+		Morse::Short,
+	])
 }
 
 pub enum Running {
-	SubProcess { inner: Option<Child> },
-	Play { task: JoinHandle<Result<()>> },
+	SubProcess {
+		inner: Option<Child>,
+	},
+	Play {
+		task: JoinHandle<Result<()>>,
+		abort: Arc<AtomicBool>,
+		/// Incoming messages wait for it instead of cutting it off
+		finish: bool,
+	},
+	Record {
+		task: JoinHandle<Result<()>>,
+		stop: Arc<AtomicBool>,
+	},
 }
 
 impl Running {
+	/// A hands-free recording that hasn't stopped yet
+	pub fn recording(&self) -> bool {
+		matches!(self, Running::Record { task, .. } if !task.is_finished())
+	}
+
+	/// A message or sound still playing
+	pub fn playing(&self) -> bool {
+		matches!(self, Running::Play { task, .. } if !task.is_finished())
+	}
+
+	/// Still going and not to be cut off by an incoming message,
+	/// only loop tape playback and subprocesses are
+	pub fn holds_playback(&self) -> bool {
+		self.recording()
+			|| matches!(self, Running::Play { task, finish: true, .. } if !task.is_finished())
+	}
+
 	pub async fn terminate(mut self) {
 		match &mut self {
-			Running::Play { task } => {
-				// In general, playback should be abortable
-				// But that's also for voice messages, so TODO
+			Running::Play { task, abort, .. } | Running::Record { task, stop: abort } => {
+				abort.store(true, Ordering::Relaxed);
 				task.await.ok();
 			}
 			Running::SubProcess { inner } => {
@@ -120,7 +314,7 @@ impl Running {
 impl Drop for Running {
 	fn drop(&mut self) {
 		match self {
-			Running::Play { .. } => (), // whatev
+			Running::Play { .. } | Running::Record { .. } => (), // whatev
 			Running::SubProcess { inner } => {
 				if let Some(child) = inner.take() {
 					tokio::spawn(terminate_child(child));
@@ -130,6 +324,75 @@ impl Drop for Running {
 	}
 }
 
+/// What a running Sequence or Parallel shares between its steps
+struct StepCtx {
+	dir: PathBuf,
+	messages: Sender<Outgoing>,
+	room: Option<OwnedRoomId>,
+	abort: Arc<AtomicBool>,
+}
+
+async fn pause(time: Duration, abort: &AtomicBool) {
+	let end = Instant::now() + time;
+	while !abort.load(Ordering::Relaxed) && Instant::now() < end {
+		sleep((end - Instant::now()).min(STEP_POLL)).await;
+	}
+}
+
+fn run_step(step: Step, ctx: Arc<StepCtx>) -> BoxFuture<'static, Result<()>> {
+	async move {
+		if ctx.abort.load(Ordering::Relaxed) {
+			return Ok(());
+		}
+		debug!(?step, "Step");
+		match step {
+			Step::Sequence { steps } => {
+				for step in steps {
+					run_step(step, ctx.clone()).await?;
+				}
+			}
+			Step::Parallel { steps } => {
+				try_join_all(steps.into_iter().map(|step| run_step(step, ctx.clone()))).await?;
+			}
+			Step::Delay { time } => pause(time, &ctx.abort).await,
+			Step::PlaySound { file } => {
+				let ctx = ctx.clone();
+				tokio::task::spawn_blocking(move || {
+					audio::play_file(&ctx.dir.join(file), &ctx.abort).map(|_| ())
+				})
+				.await??;
+			}
+			Step::SendText { text } => {
+				let room = ctx.room.clone();
+				ctx.messages
+					.send(Outgoing::Text { body: text, room })
+					.await?;
+			}
+			Step::SendAudio { file } => {
+				let file = ctx.dir.join(file);
+				let mut rec =
+					tokio::task::spawn_blocking(move || audio::load_file(&file)).await??;
+				rec.room = ctx.room.clone();
+				ctx.messages.send(rec.into()).await?;
+			}
+			Step::Led { pattern, time } => {
+				let room = ctx.room.clone();
+				ctx.messages
+					.send(Outgoing::Led {
+						pattern,
+						time,
+						room,
+					})
+					.await?;
+				let _pattern = status::pattern(pattern);
+				pause(time, &ctx.abort).await;
+			}
+		}
+		Ok(())
+	}
+	.boxed()
+}
+
 async fn terminate_child(mut child: Child) {
 	child.interrupt().ok();
 	for _ in 0..10 {
@@ -149,40 +412,191 @@ async fn terminate_child(mut child: Child) {
 }
 
 pub struct ButtonCommands {
+	file: PathBuf,
+	loaded: Mutex<Loaded>,
+	target: Mutex<Option<(OwnedRoomId, Instant)>>,
+	rooms: Vec<OwnedRoomId>,
+	history: History,
+}
+
+/// Everything that comes from the cmds file and gets replaced on reload
+struct Loaded {
 	cmds: Cmds,
 	tape: Option<audio::LoopTape>,
+	mtime: Option<SystemTime>,
+}
+
+/// Media kept locally, for replaying
+#[derive(Clone)]
+pub struct History {
+	pub received: Arc<Queue>,
+	pub sent: Arc<Queue>,
+}
+
+fn mtime(file: &Path) -> Option<SystemTime> {
+	file.metadata().and_then(|m| m.modified()).ok()
+}
+
+/// Room targets have to be among rooms, the ones this device runs in
+fn parse(file: &Path, rooms: &[OwnedRoomId]) -> Result<Cmds> {
+	if !file.exists() {
+		return Ok(HashMap::new());
+	}
+	let file = read(file).context("Open cmd file")?;
+	let cmds = serde_yaml::from_slice::<Cmds>(&file).context("Parse cmd file")?;
+	for (word, cmd) in &cmds {
+		match cmd {
+			Command::SubProcess { cmd } => ensure!(!cmd.is_empty(), "Empty command for {word:?}"),
+			Command::Room { room } => ensure!(
+				rooms.contains(room),
+				"{word:?}: {room} isn't one of the rooms this device runs in"
+			),
+			_ => (),
+		}
+	}
+	debug!(?cmds);
+	Ok(cmds)
+}
+
+fn tape_time(cmds: &Cmds) -> Option<Duration> {
+	cmds.values()
+		.filter_map(|cmd| match cmd {
+			Command::LoopTape { time, .. } => Some(*time),
+			_ => None,
+		})
+		.max()
 }
 
 impl ButtonCommands {
-	#[tracing::instrument]
-	pub fn load(cfg_dir: &Path) -> Result<ButtonCommands> {
-		let file = &cfg_dir.join(MORSE_CMDS);
-		let file = match file.exists() {
-			true => read(file).context("Open cmd file")?,
-			false => {
-				return Ok(ButtonCommands {
-					cmds: HashMap::new(),
-					tape: None,
-				})
+	#[tracing::instrument(skip(history))]
+	pub fn load(
+		cfg_dir: &Path,
+		history: History,
+		rooms: Vec<OwnedRoomId>,
+	) -> Result<ButtonCommands> {
+		let file = cfg_dir.join(MORSE_CMDS);
+		let mtime = mtime(&file);
+		let cmds = parse(&file, &rooms)?;
+		let tape = tape_time(&cmds).map(audio::LoopTape::start);
+		Ok(ButtonCommands {
+			file,
+			loaded: Mutex::new(Loaded { cmds, tape, mtime }),
+			target: Mutex::new(None),
+			rooms,
+			history,
+		})
+	}
+
+	/// Replaces the commands if the file parses, keeps the old ones otherwise
+	#[tracing::instrument(skip(self))]
+	pub fn reload(&self) {
+		let mtime = mtime(&self.file);
+		let cmds = match parse(&self.file, &self.rooms) {
+			Ok(cmds) => cmds,
+			Err(e) => {
+				error!("Keeping previous commands, {:?}: {e:#}", self.file);
+				// Don't complain again until the file changes
+				self.loaded.lock().unwrap().mtime = mtime;
+				return;
 			}
 		};
-		let cmds = serde_yaml::from_slice::<Cmds>(&file).context("Parse cmd file")?;
-		debug!(?cmds);
-		let tape = cmds
-			.iter()
-			.filter_map(|cmd| match cmd {
-				(_, Command::LoopTape { time, .. }) => Some(*time),
-				_ => None,
-			})
-			.max()
-			.map(audio::LoopTape::start);
-		Ok(ButtonCommands { cmds, tape })
-	}
-
-	pub(crate) fn exec(&self, cmd: MorseWord, messages: &Sender<audio::Rec>) -> Option<Running> {
-		self.cmds.get(&cmd).and_then(|cmd| match &cmd {
+		let mut loaded = self.loaded.lock().unwrap();
+		match (tape_time(&cmds), &loaded.tape) {
+			(Some(time), Some(tape)) => tape.resize(time),
+			(Some(time), None) => loaded.tape = Some(audio::LoopTape::start(time)),
+			(None, _) => loaded.tape = None,
+		}
+		info!(count = cmds.len(), "Reloaded commands");
+		loaded.cmds = cmds;
+		loaded.mtime = mtime;
+	}
+
+	/// Reloads on SIGHUP, or when the file's modification time changes
+	pub async fn watch(self: Arc<Self>) -> Result<()> {
+		let mut sighup = signal(SignalKind::hangup())?;
+		loop {
+			let hup = tokio::select! {
+				_ = sighup.recv() => true,
+				_ = sleep(RELOAD_POLL) => false,
+			};
+			let changed = mtime(&self.file) != self.loaded.lock().unwrap().mtime;
+			if hup || changed {
+				let this = self.clone();
+				tokio::task::spawn_blocking(move || this.reload()).await?;
+			}
+		}
+	}
+
+	/// Room chosen for the next recording, if it was chosen recently
+	pub(crate) fn take_target(&self) -> Option<OwnedRoomId> {
+		match self.target.lock().unwrap().take() {
+			Some((room, chosen)) if chosen.elapsed() < TARGET_TIMEOUT => Some(room),
+			_ => None,
+		}
+	}
+
+	fn replay(&self, queue: Arc<Queue>, count: usize) -> Option<Running> {
+		let entries = queue.recent(count);
+		debug!(wanted = count, found = entries.len(), "Replay");
+		if entries.is_empty() {
+			return None;
+		}
+		let abort = Arc::new(AtomicBool::new(false));
+		let task = tokio::task::spawn_blocking({
+			let abort = abort.clone();
+			move || audio::replay(&queue, &entries, &abort).map(|_| ())
+		});
+		Some(Running::Play {
+			task,
+			abort,
+			finish: true,
+		})
+	}
+
+	fn run_steps(&self, step: Step, messages: &Sender<Outgoing>) -> Running {
+		let abort = Arc::new(AtomicBool::new(false));
+		let ctx = Arc::new(StepCtx {
+			dir: self.dir().to_owned(),
+			messages: messages.clone(),
+			room: self.take_target(),
+			abort: abort.clone(),
+		});
+		let task = tokio::spawn(async move {
+			let res = run_step(step, ctx).await;
+			if let Err(e) = &res {
+				error!("Command steps failed: {e:#}");
+			}
+			res
+		});
+		Running::Play {
+			task,
+			abort,
+			finish: true,
+		}
+	}
+
+	pub(crate) fn exclusive(&self, cmd: &MorseWord) -> Option<Exclusive> {
+		match self.loaded.lock().unwrap().cmds.get(cmd) {
+			Some(Command::Keyer { end }) => Some(Exclusive::Keyer { end: end.clone() }),
+			Some(Command::Calibrate) => Some(Exclusive::Calibrate),
+			_ => None,
+		}
+	}
+
+	/// Where the cmd file is, other files are relative to it
+	pub(crate) fn dir(&self) -> &Path {
+		self.file.parent().unwrap_or(Path::new("."))
+	}
+
+	pub(crate) fn knows(&self, cmd: &MorseWord) -> bool {
+		self.loaded.lock().unwrap().cmds.contains_key(cmd)
+	}
+
+	pub(crate) fn exec(&self, cmd: MorseWord, messages: &Sender<Outgoing>) -> Option<Running> {
+		let loaded = self.loaded.lock().unwrap();
+		loaded.cmds.get(&cmd).and_then(|cmd| match &cmd {
 			Command::SubProcess { cmd } => match &cmd[..] {
-				[] => unreachable!(),
+				[] => unreachable!("Checked on load"),
 				[path, args @ ..] => Some(Running::SubProcess {
 					inner: process::Command::new(path)
 						.args(args)
@@ -192,27 +606,61 @@ impl ButtonCommands {
 				}),
 			},
 			Command::LoopTape { time, play, send } => {
-				let samp = self
+				let samp = loaded
 					.tape
 					.as_ref()
 					.expect("Initialized to illegal state")
 					.get(*time);
 				debug!(?time, ?play, ?send, samp_len = samp.len(), "LoopTape use");
 				if *send {
-					match audio::encode_raw(&samp) {
-						Ok(enc) => {
-							messages.try_send(enc).ok();
+					match audio::process_recording(&samp).map(|speech| audio::encode_raw(&speech)) {
+						Some(Ok(mut enc)) => {
+							enc.room = self.take_target();
+							messages.try_send(enc.into()).ok();
 						}
-						Err(e) => error!("Failed to encode accumulated recording: {}", e),
+						Some(Err(e)) => error!("Failed to encode accumulated recording: {}", e),
+						None => info!("Loop tape was only silence, not sending"),
 					}
 				}
 				if *play {
-					let task = tokio::task::spawn_blocking(move || audio::play_raw(&samp, 1));
-					Some(Running::Play { task })
+					let abort = Arc::new(AtomicBool::new(false));
+					let task = tokio::task::spawn_blocking({
+						let abort = abort.clone();
+						move || audio::play_raw(&samp, 1, &abort).map(|_| ())
+					});
+					Some(Running::Play {
+						task,
+						abort,
+						finish: false,
+					})
 				} else {
 					None
 				}
 			}
+			Command::Room { room } => {
+				debug!(?room, "Next recording goes to");
+				*self.target.lock().unwrap() = Some((room.clone(), Instant::now()));
+				None
+			}
+			Command::HandsFree => {
+				let room = self.take_target();
+				let recording = audio::RecProc::start(true, room, messages.clone());
+				let stop = recording.stop_flag();
+				let task = tokio::spawn(recording.wait());
+				Some(Running::Record { task, stop })
+			}
+			Command::Dnd => {
+				crate::dnd::toggle();
+				None
+			}
+			Command::ReplayReceived { count } => self.replay(self.history.received.clone(), *count),
+			Command::ReplaySent => self.replay(self.history.sent.clone(), 1),
+			Command::Sequence { .. } | Command::Parallel { .. } | Command::SendCanned(_) => {
+				let step = cmd.step().expect("Command with steps");
+				Some(self.run_steps(step, messages))
+			}
+			// Need the button, see button::read
+			Command::Keyer { .. } | Command::Calibrate => None,
 		})
 	}
 }
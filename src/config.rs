@@ -0,0 +1,426 @@
+use anyhow::{ensure, Context, Result};
+use matrix_sdk::ruma::OwnedUserId;
+use serde::{Deserialize, Serialize};
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	fs::{self, read},
+	ops::RangeInclusive,
+	path::Path,
+	path::PathBuf,
+	time::Duration,
+};
+use tracing::debug;
+
+use crate::audio::{AudioKind, DspStage};
+
+pub(crate) static SETTINGS: &str = "config.yaml";
+/// Written by the calibration command
+pub(crate) static CALIBRATION: &str = "calibration.yaml";
+
+/// Per-device settings, all optional
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+	#[serde(default)]
+	pub audio: AudioSettings,
+	/// Speaks incoming text messages: gets the text on stdin, should write a WAV file to stdout.
+	/// E.g. [espeak-ng, --stdout]
+	pub tts: Option<Vec<String>>,
+	pub transcribe: Option<Transcribe>,
+	#[serde(default)]
+	pub playback: PlaybackSettings,
+	#[serde(default)]
+	pub dnd: DndSettings,
+	#[serde(default)]
+	pub morse: MorseSettings,
+}
+
+/// Button timing for Morse commands
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct MorseSettings {
+	/// Presses longer than this are dashes, or start a recording
+	#[serde(deserialize_with = "deser_humantime")]
+	pub long_press: Duration,
+	/// A pause this long ends a command
+	#[serde(deserialize_with = "deser_humantime")]
+	pub word_timeout: Duration,
+	/// Follow the pace of whoever is pressing, starting from the values above
+	pub adaptive: bool,
+	/// Beep for each dot and dash and for found or unknown commands, not just flash the LEDs
+	pub sidetone: bool,
+}
+
+impl Default for MorseSettings {
+	fn default() -> Self {
+		MorseSettings {
+			long_press: Duration::from_millis(250),
+			word_timeout: Duration::from_secs(2),
+			adaptive: false,
+			sidetone: false,
+		}
+	}
+}
+
+/// Sane button timing, anything outside is a typo or a broken measurement
+const LONG_PRESS: RangeInclusive<Duration> = Duration::from_millis(20)..=Duration::from_secs(5);
+const WORD_TIMEOUT: RangeInclusive<Duration> = Duration::from_millis(100)..=Duration::from_secs(60);
+
+fn check_timing(long_press: Duration, word_timeout: Duration) -> Result<()> {
+	let within = |name, value: Duration, range: &RangeInclusive<Duration>| {
+		ensure!(
+			range.contains(&value),
+			"{name} of {} isn't between {} and {}",
+			humantime::format_duration(value),
+			humantime::format_duration(*range.start()),
+			humantime::format_duration(*range.end()),
+		);
+		Ok(())
+	};
+	within("long_press", long_press, &LONG_PRESS)?;
+	within("word_timeout", word_timeout, &WORD_TIMEOUT)
+}
+
+impl MorseSettings {
+	fn check(&self) -> Result<()> {
+		check_timing(self.long_press, self.word_timeout).context("morse")
+	}
+}
+
+/// Measured by the calibration command, overrides long_press and word_timeout.
+/// Delete the file to go back to the configured values.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Calibration {
+	#[serde(deserialize_with = "deser_humantime", serialize_with = "ser_humantime")]
+	pub long_press: Duration,
+	#[serde(deserialize_with = "deser_humantime", serialize_with = "ser_humantime")]
+	pub word_timeout: Duration,
+}
+
+impl Calibration {
+	pub fn load(cfg_dir: &Path) -> Result<Option<Calibration>> {
+		let file = &cfg_dir.join(CALIBRATION);
+		if !file.exists() {
+			return Ok(None);
+		}
+		let file = read(file).context("Open calibration file")?;
+		let calibration: Option<Calibration> =
+			serde_yaml::from_slice(&file).context("Parse calibration file")?;
+		calibration.as_ref().map(Calibration::check).transpose()?;
+		Ok(calibration)
+	}
+
+	pub fn check(&self) -> Result<()> {
+		check_timing(self.long_press, self.word_timeout).context("Calibration")
+	}
+
+	/// Through a temporary file, like the session
+	pub fn save(&self, cfg_dir: &Path) -> Result<()> {
+		let path = cfg_dir.join(CALIBRATION);
+		let tmp_path = path.with_extension("yaml.tmp");
+		let file = fs::File::create(&tmp_path).context("Open calibration file")?;
+		serde_yaml::to_writer(&file, self).context("Write calibration file")?;
+		file.sync_all().context("Write calibration file")?;
+		fs::rename(&tmp_path, &path).context("Replace calibration file")?;
+		Ok(())
+	}
+}
+
+/// Do not disturb: messages stay queued instead of being played
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DndSettings {
+	/// Quiet times, e.g. [{from: "22:00", to: "7:00"}]
+	#[serde(default)]
+	pub schedule: Vec<Quiet>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Quiet {
+	pub from: TimeOfDay,
+	pub to: TimeOfDay,
+}
+
+/// Loudness of played messages
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct PlaybackSettings {
+	/// Target loudness (dBFS RMS) for incoming messages, e.g. -20. Unset plays them as recorded.
+	pub normalize: Option<f32>,
+	/// Device volume in dB, applied to everything played
+	pub volume: f32,
+	/// Volume by time of day, e.g. [{from: "22:00", to: "7:00", volume: -15}].
+	/// The first matching profile wins, volume is used outside all of them.
+	pub profiles: Vec<VolumeProfile>,
+	/// Don't play messages as they arrive, signal them and play one per button tap
+	pub on_demand: bool,
+	/// Sound played before each message
+	pub chime: Option<Chime>,
+}
+
+/// Sound files (relative to the config directory) played before a message, so you know who's talking
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Chime {
+	/// For senders without their own, a short ding if not given
+	pub default: Option<PathBuf>,
+	/// By user ID, e.g. {"@kitchen:example.org": kitchen.ogg}
+	#[serde(default)]
+	pub senders: HashMap<OwnedUserId, PathBuf>,
+}
+
+impl Default for PlaybackSettings {
+	fn default() -> Self {
+		PlaybackSettings {
+			normalize: None,
+			volume: 0.0,
+			profiles: Vec::new(),
+			on_demand: false,
+			chime: None,
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeProfile {
+	pub from: TimeOfDay,
+	pub to: TimeOfDay,
+	/// dB, replaces the device volume
+	pub volume: f32,
+}
+
+/// Minutes since midnight, written as HH:MM
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "Cow<str>")]
+pub struct TimeOfDay(pub u16);
+
+impl TryFrom<Cow<'_, str>> for TimeOfDay {
+	type Error = anyhow::Error;
+	fn try_from(value: Cow<str>) -> Result<Self, Self::Error> {
+		let (h, m) = value
+			.split_once(':')
+			.with_context(|| format!("Expected HH:MM, got {value}"))?;
+		let (h, m) = (h.trim().parse::<u16>()?, m.trim().parse::<u16>()?);
+		ensure!(h < 24 && m < 60, "No such time of day: {value}");
+		Ok(TimeOfDay(h * 60 + m))
+	}
+}
+
+impl TimeOfDay {
+	/// Local time
+	pub fn now() -> TimeOfDay {
+		use chrono::Timelike;
+		let now = chrono::Local::now();
+		TimeOfDay((now.hour() * 60 + now.minute()) as u16)
+	}
+
+	/// From inclusive, to exclusive, possibly over midnight
+	pub fn within(self, from: TimeOfDay, to: TimeOfDay) -> bool {
+		match from.0 <= to.0 {
+			true => from.0 <= self.0 && self.0 < to.0,
+			false => from.0 <= self.0 || self.0 < to.0,
+		}
+	}
+}
+
+/// Speech-to-text for outgoing recordings
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Transcribe {
+	/// Gets a mono WAV recording on stdin, should print the text to stdout
+	pub cmd: Vec<String>,
+	#[serde(default)]
+	pub mode: TranscribeMode,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TranscribeMode {
+	/// Wait for the transcription and use it as the message body
+	#[default]
+	Body,
+	/// Send right away, post the transcription as a thread reply
+	Thread,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AudioSettings {
+	/// Overridden by --audio
+	pub backend: Option<AudioKind>,
+	/// Sample rate for backends that support choosing one
+	pub rate: Option<u32>,
+	/// ALSA device name (e.g. hw:1,0)
+	pub device: Option<String>,
+	/// Directory for the wav-dir backend
+	pub dir: Option<PathBuf>,
+	/// Fallback for incoming audio that isn't OGG Opus or Vorbis, WAV, MP3, AAC/M4A or FLAC:
+	/// gets the file on stdin, should write WAV to stdout. E.g. [ffmpeg, -i, -, -f, wav, -]
+	pub decoder: Option<Vec<String>>,
+	#[serde(default)]
+	pub vad: VadSettings,
+	#[serde(default)]
+	pub recording: RecordingSettings,
+	/// Processing of recordings before they are sent, try it with the dsp subcommand
+	#[serde(default)]
+	pub dsp: Vec<DspStage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct RecordingSettings {
+	/// Longest single message
+	#[serde(deserialize_with = "deser_humantime")]
+	pub max_length: Duration,
+	/// Blink this long before the recording is cut off
+	#[serde(deserialize_with = "deser_humantime")]
+	pub warning: Duration,
+	/// Longer recordings are sent as up to this many consecutive messages of max_length
+	pub chunks: u32,
+}
+
+impl Default for RecordingSettings {
+	fn default() -> Self {
+		RecordingSettings {
+			max_length: Duration::from_secs(20),
+			warning: Duration::from_secs(3),
+			chunks: 1,
+		}
+	}
+}
+
+impl RecordingSettings {
+	fn check(&self) -> Result<()> {
+		ensure!(
+			self.max_length > self.warning,
+			"recording.max_length ({}) must be longer than recording.warning ({})",
+			humantime::format_duration(self.max_length),
+			humantime::format_duration(self.warning),
+		);
+		ensure!(self.chunks >= 1, "recording.chunks must be at least 1");
+		Ok(())
+	}
+}
+
+/// Voice activity detection, used to trim recordings and end hands-free ones
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct VadSettings {
+	/// Frames louder than this (dBFS) count as speech
+	pub threshold: f32,
+	/// Silence that ends a hands-free recording
+	#[serde(deserialize_with = "deser_humantime")]
+	pub silence: Duration,
+	/// Silence kept before and after speech when trimming
+	#[serde(deserialize_with = "deser_humantime")]
+	pub pad: Duration,
+}
+
+impl Default for VadSettings {
+	fn default() -> Self {
+		VadSettings {
+			threshold: -40.0,
+			silence: Duration::from_millis(1500),
+			pad: Duration::from_millis(200),
+		}
+	}
+}
+
+pub(crate) fn deser_humantime<'de, D: serde::Deserializer<'de>>(
+	de: D,
+) -> Result<Duration, D::Error> {
+	Ok(String::deserialize(de)?
+		.parse::<humantime::Duration>()
+		.map_err(serde::de::Error::custom)?
+		.into())
+}
+
+fn ser_humantime<S: serde::Serializer>(d: &Duration, ser: S) -> Result<S::Ok, S::Error> {
+	let ms = Duration::from_millis(d.as_millis() as u64);
+	ser.serialize_str(&humantime::format_duration(ms).to_string())
+}
+
+impl Settings {
+	#[tracing::instrument]
+	pub fn load(cfg_dir: &Path) -> Result<Settings> {
+		let file = &cfg_dir.join(SETTINGS);
+		let mut settings: Settings = match file.exists() {
+			false => Settings::default(),
+			true => {
+				let file = read(file).context("Open settings file")?;
+				serde_yaml::from_slice(&file).context("Parse settings file")?
+			}
+		};
+		if let Some(chime) = &mut settings.playback.chime {
+			let paths = chime.default.iter_mut().chain(chime.senders.values_mut());
+			for path in paths {
+				*path = cfg_dir.join(&*path);
+			}
+		}
+		settings.audio.recording.check()?;
+		settings.morse.check()?;
+		for stage in &settings.audio.dsp {
+			stage.check(settings.audio.rate)?;
+		}
+		if let Some(calibration) = Calibration::load(cfg_dir)? {
+			settings.morse.long_press = calibration.long_press;
+			settings.morse.word_timeout = calibration.word_timeout;
+		}
+		debug!(?settings);
+		Ok(settings)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn at(time: &str) -> TimeOfDay {
+		TimeOfDay::try_from(Cow::from(time)).unwrap()
+	}
+
+	#[test]
+	fn time_of_day() {
+		assert_eq!(at("07:30").0, 7 * 60 + 30);
+		assert_eq!(at(" 0:05").0, 5);
+		for bad in ["24:00", "12:60", "noon", "12"] {
+			assert!(TimeOfDay::try_from(Cow::from(bad)).is_err(), "{bad}");
+		}
+	}
+
+	#[test]
+	fn within_over_midnight() {
+		let (from, to) = (at("22:00"), at("07:00"));
+		for inside in ["22:00", "23:59", "00:00", "03:00", "06:59"] {
+			assert!(at(inside).within(from, to), "{inside}");
+		}
+		for outside in ["07:00", "12:00", "21:59"] {
+			assert!(!at(outside).within(from, to), "{outside}");
+		}
+	}
+
+	#[test]
+	fn morse_timing() {
+		assert!(MorseSettings::default().check().is_ok());
+		let ms = Duration::from_millis;
+		assert!(check_timing(Duration::ZERO, ms(2000)).is_err());
+		assert!(check_timing(ms(250), Duration::ZERO).is_err());
+		assert!(check_timing(ms(250), Duration::from_secs(3600)).is_err());
+		// Calibration can measure long presses beyond seven dots, that's fine
+		assert!(check_timing(ms(800), ms(700)).is_ok());
+	}
+
+	#[test]
+	fn within_same_day() {
+		let (from, to) = (at("09:00"), at("17:00"));
+		assert!(at("09:00").within(from, to));
+		assert!(at("16:59").within(from, to));
+		assert!(!at("17:00").within(from, to));
+		assert!(!at("08:59").within(from, to));
+		assert!(!at("23:00").within(from, to));
+	}
+}
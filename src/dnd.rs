@@ -0,0 +1,146 @@
+use matrix_sdk::ruma::OwnedRoomId;
+use once_cell::sync::OnceCell;
+use std::{
+	collections::HashSet,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Mutex,
+	},
+	time::Duration,
+};
+use tokio::{sync::Notify, time::timeout};
+use tracing::info;
+
+use crate::{
+	config::{DndSettings, Quiet, TimeOfDay},
+	status::{self, Waiting},
+};
+
+/// Line in a room topic that switches do not disturb on or off
+pub(crate) static TOPIC_PREFIX: &str = "gegensprech-dnd:";
+/// How often a waiting playback checks the schedule
+const RECHECK: Duration = Duration::from_secs(30);
+
+struct Dnd {
+	schedule: Vec<Quiet>,
+	/// Never auto-play, every message waits for a press
+	on_demand: bool,
+	state: Mutex<State>,
+	changed: Notify,
+	waiting: AtomicBool,
+	released: AtomicBool,
+}
+
+#[derive(Debug)]
+struct State {
+	/// Set by Morse command, overrides the rest until the schedule or a topic changes
+	manual: Option<bool>,
+	/// Rooms whose topic asks for quiet
+	rooms: HashSet<OwnedRoomId>,
+	scheduled: bool,
+}
+
+static DND: OnceCell<Dnd> = OnceCell::new();
+
+fn dnd() -> &'static Dnd {
+	DND.get_or_init(|| Dnd::new(Vec::new(), false))
+}
+
+pub(crate) fn init(settings: &DndSettings, on_demand: bool) {
+	if DND
+		.set(Dnd::new(settings.schedule.clone(), on_demand))
+		.is_err()
+	{
+		tracing::error!("Can init do not disturb only once");
+	}
+}
+
+impl Dnd {
+	fn new(schedule: Vec<Quiet>, on_demand: bool) -> Dnd {
+		Dnd {
+			schedule,
+			on_demand,
+			state: Mutex::new(State {
+				manual: None,
+				rooms: HashSet::new(),
+				scheduled: false,
+			}),
+			changed: Notify::new(),
+			waiting: AtomicBool::new(false),
+			released: AtomicBool::new(false),
+		}
+	}
+
+	fn active(&self) -> bool {
+		let now = TimeOfDay::now();
+		let scheduled = self.schedule.iter().any(|q| now.within(q.from, q.to));
+		let mut state = self.state.lock().unwrap();
+		if scheduled != state.scheduled {
+			info!(scheduled, "Do not disturb schedule");
+			state.scheduled = scheduled;
+			state.manual = None;
+		}
+		state
+			.manual
+			.unwrap_or(state.scheduled || !state.rooms.is_empty())
+	}
+
+	fn update(&self, f: impl FnOnce(&mut State)) {
+		let mut state = self.state.lock().unwrap();
+		f(&mut state);
+		info!(?state, "Do not disturb");
+		drop(state);
+		self.changed.notify_one();
+	}
+}
+
+/// Morse command
+pub(crate) fn toggle() {
+	let on = !dnd().active();
+	dnd().update(|state| state.manual = Some(on));
+}
+
+/// From a room topic line like "gegensprech-dnd: on"
+pub(crate) fn set_room(room: OwnedRoomId, on: bool) {
+	dnd().update(|state| {
+		let changed = match on {
+			true => state.rooms.insert(room),
+			false => state.rooms.remove(&room),
+		};
+		if changed {
+			state.manual = None;
+		}
+	});
+}
+
+/// Lets one held back message through. False if nothing was waiting.
+pub(crate) fn release() -> bool {
+	let dnd = dnd();
+	if !dnd.waiting.load(Ordering::Relaxed) {
+		return false;
+	}
+	dnd.released.store(true, Ordering::Relaxed);
+	dnd.changed.notify_one();
+	true
+}
+
+/// Returns once the next message may be played
+pub(crate) async fn wait() {
+	let dnd = dnd();
+	let mut indicator = None;
+	loop {
+		let why = match (dnd.on_demand, dnd.active()) {
+			(true, _) => Waiting::OnDemand,
+			(false, true) => Waiting::Dnd,
+			(false, false) => break,
+		};
+		if dnd.released.swap(false, Ordering::Relaxed) {
+			break;
+		}
+		dnd.waiting.store(true, Ordering::Relaxed);
+		indicator.get_or_insert_with(|| status::waiting(why));
+		timeout(RECHECK, dnd.changed.notified()).await.ok();
+	}
+	dnd.waiting.store(false, Ordering::Relaxed);
+	dnd.released.store(false, Ordering::Relaxed);
+}
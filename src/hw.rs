@@ -0,0 +1,46 @@
+mod keyboard;
+mod rpi;
+pub(crate) mod scripted;
+
+use anyhow::Result;
+use rppal::gpio::Level;
+use std::time::Instant;
+
+use crate::{
+	status::{Render, Status},
+	Hardware, Scripted,
+};
+
+/// Raw edges of an active-low button, without debouncing
+pub(crate) trait Edges: Send {
+	fn current(&self) -> Level;
+	/// Wait for the next edge. Returns false on timeout.
+	fn next(&mut self, timeout: Option<Instant>) -> Result<bool>;
+}
+
+pub(crate) trait Backend {
+	fn button(&mut self) -> Result<Option<Box<dyn Edges>>>;
+	fn leds(&mut self) -> Result<Box<dyn Render + Send>>;
+}
+
+#[tracing::instrument]
+pub(crate) fn from_args(args: &Hardware) -> Result<Box<dyn Backend>> {
+	Ok(match args {
+		Hardware::Seeed2Mic | Hardware::SolderedCustom(_) => Box::new(rpi::Rpi::new(args.clone())?),
+		Hardware::Keyboard => Box::new(keyboard::Keyboard::start()),
+		Hardware::Scripted(Scripted { script }) => Box::new(scripted::Scripted::load(script)?),
+	})
+}
+
+/// For backends without LEDs: show status changes on the terminal
+struct PrintStatus(Option<String>);
+
+impl Render for PrintStatus {
+	fn render(&mut self, status: &Status) {
+		let now = format!("{status:?}");
+		if self.0.as_ref() != Some(&now) {
+			eprintln!("{now}");
+			self.0 = Some(now);
+		}
+	}
+}
@@ -0,0 +1,105 @@
+use anyhow::{bail, Result};
+use rppal::gpio::Level;
+use std::{
+	io::stdin,
+	sync::{
+		mpsc::{channel, Receiver, RecvTimeoutError},
+		Arc, Mutex,
+	},
+	thread::{self, sleep},
+	time::{Duration, Instant},
+};
+
+use super::{Backend, Edges, PrintStatus};
+use crate::status::Render;
+
+/// Button on stdin, for running on a desktop.
+/// An empty line (just Enter) toggles the button between pressed and released,
+/// `.` and `-` in a line are played back as short and long presses.
+pub(crate) struct Keyboard {
+	edges: Option<KeyEdges>,
+}
+
+struct KeyEdges {
+	level: Arc<Mutex<Level>>,
+	edges: Receiver<()>,
+}
+
+impl Keyboard {
+	pub fn start() -> Self {
+		let level = Arc::new(Mutex::new(Level::High));
+		let (tx, edges) = channel();
+		let level_write = level.clone();
+		thread::spawn(move || {
+			let set = |level: Level| {
+				*level_write.lock().unwrap() = level;
+				tx.send(()).ok();
+			};
+			let press = |time: Duration| {
+				set(Level::Low);
+				sleep(time);
+				set(Level::High);
+				sleep(Duration::from_millis(150));
+			};
+			eprintln!("Button on stdin: Enter toggles, . and - press short and long");
+			for line in stdin().lines() {
+				let line = match line {
+					Ok(line) => line,
+					Err(_) => break,
+				};
+				if line.trim().is_empty() {
+					let current = *level_write.lock().unwrap();
+					set(!current);
+					continue;
+				}
+				for c in line.chars() {
+					match c {
+						'.' => press(Duration::from_millis(50)),
+						'-' | '_' => press(Duration::from_millis(500)),
+						_ => (),
+					}
+				}
+			}
+		});
+		Keyboard {
+			edges: Some(KeyEdges { level, edges }),
+		}
+	}
+}
+
+impl Backend for Keyboard {
+	fn button(&mut self) -> Result<Option<Box<dyn Edges>>> {
+		Ok(self.edges.take().map(|e| Box::new(e) as Box<dyn Edges>))
+	}
+
+	fn leds(&mut self) -> Result<Box<dyn Render + Send>> {
+		Ok(Box::new(PrintStatus(None)))
+	}
+}
+
+impl Edges for KeyEdges {
+	fn current(&self) -> Level {
+		*self.level.lock().unwrap()
+	}
+
+	fn next(&mut self, timeout: Option<Instant>) -> Result<bool> {
+		let res = match timeout {
+			Some(timeout) => self
+				.edges
+				.recv_timeout(timeout.saturating_duration_since(Instant::now())),
+			None => self
+				.edges
+				.recv()
+				.map_err(|_| RecvTimeoutError::Disconnected),
+		};
+		match (res, timeout) {
+			(Ok(()), _) => Ok(true),
+			(Err(RecvTimeoutError::Timeout), _) => Ok(false),
+			(Err(RecvTimeoutError::Disconnected), Some(timeout)) => {
+				sleep(timeout.saturating_duration_since(Instant::now()));
+				Ok(false)
+			}
+			(Err(RecvTimeoutError::Disconnected), None) => bail!("stdin closed"),
+		}
+	}
+}
@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use rppal::{
+	gpio::{Gpio, InputPin, Level, Pin, Trigger},
+	system::DeviceInfo,
+};
+use std::time::Instant;
+use tracing::{info, trace};
+
+use super::{Backend, Edges};
+use crate::{
+	status::{RGBLed, Render, Seeed},
+	Hardware, SolderedCustom,
+};
+
+pub(crate) struct Rpi {
+	gpio: Gpio,
+	hardware: Hardware,
+}
+
+impl Rpi {
+	pub fn new(hardware: Hardware) -> Result<Self> {
+		info!(raspi=?DeviceInfo::new());
+		let gpio = Gpio::new().context("Open GPIO for Pins")?;
+		Ok(Self { gpio, hardware })
+	}
+}
+
+impl Backend for Rpi {
+	fn button(&mut self) -> Result<Option<Box<dyn Edges>>> {
+		let button = match &self.hardware {
+			Hardware::Seeed2Mic => Some(17),
+			Hardware::SolderedCustom(SolderedCustom { button, .. }) => *button,
+			_ => unreachable!("Not a Raspberry Pi backend"),
+		};
+		button
+			.map(|button| {
+				let pin = self.gpio.get(button).context("Open button pin")?;
+				Ok(Box::new(ButtonPin::new(pin)?) as Box<dyn Edges>)
+			})
+			.transpose()
+	}
+
+	fn leds(&mut self) -> Result<Box<dyn Render + Send>> {
+		Ok(match &self.hardware {
+			Hardware::Seeed2Mic => Seeed::new()?,
+			Hardware::SolderedCustom(SolderedCustom { rgb: Some(rgb), .. }) => {
+				RGBLed::new(rgb, &self.gpio)?
+			}
+			_ => Box::new(()),
+		})
+	}
+}
+
+struct ButtonPin {
+	pin: InputPin,
+}
+
+impl ButtonPin {
+	fn new(pin: Pin) -> Result<Self> {
+		let mut pin = pin.into_input_pullup();
+		pin.set_interrupt(Trigger::Both, None)?;
+		Ok(Self { pin })
+	}
+}
+
+impl Edges for ButtonPin {
+	fn current(&self) -> Level {
+		self.pin.read()
+	}
+	#[tracing::instrument(skip(self))]
+	fn next(&mut self, timeout: Option<Instant>) -> Result<bool> {
+		let ret = match timeout {
+			Some(timeout) => match timeout.checked_duration_since(Instant::now()) {
+				sleep @ Some(_) => self.pin.poll_interrupt(false, sleep)?,
+				None => None,
+			},
+			None => self.pin.poll_interrupt(false, None)?,
+		}
+		.is_some();
+		trace!(button_edge = ?ret);
+		Ok(ret)
+	}
+}
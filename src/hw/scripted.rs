@@ -0,0 +1,107 @@
+use anyhow::{bail, Context, Result};
+use rppal::gpio::Level;
+use std::{
+	collections::VecDeque,
+	fs::read_to_string,
+	path::Path,
+	thread::{park, sleep},
+	time::{Duration, Instant},
+};
+
+use super::{Backend, Edges, PrintStatus};
+use crate::status::Render;
+
+/// Plays back a fixed sequence of button levels.
+/// Each step waits for a duration (relative to the previous step), then sets the level.
+pub(crate) struct Scripted {
+	steps: Option<VecDeque<(Duration, Level)>>,
+}
+
+struct ScriptedEdges {
+	level: Level,
+	last: Instant,
+	steps: VecDeque<(Duration, Level)>,
+}
+
+impl Scripted {
+	pub fn new(steps: impl IntoIterator<Item = (Duration, Level)>) -> Self {
+		Scripted {
+			steps: Some(steps.into_iter().collect()),
+		}
+	}
+
+	/// One step per line, e.g. `1s down`, `300ms up`. Empty lines and lines starting with # are ignored.
+	#[tracing::instrument]
+	pub fn load(path: &Path) -> Result<Self> {
+		let script = read_to_string(path).context("Read button script")?;
+		let steps = script
+			.lines()
+			.enumerate()
+			.map(|(no, line)| (no + 1, line.trim()))
+			.filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+			.map(|(no, line)| {
+				let (time, level) = line
+					.rsplit_once(' ')
+					.with_context(|| format!("Line {no}: expected \"<duration> <down|up>\""))?;
+				let time = time
+					.trim()
+					.parse::<humantime::Duration>()
+					.with_context(|| format!("Line {no}: duration"))?;
+				let level = match level {
+					"down" => Level::Low,
+					"up" => Level::High,
+					l => bail!("Line {no}: Expected down or up, got {l}"),
+				};
+				Ok((time.into(), level))
+			})
+			.collect::<Result<Vec<_>>>()?;
+		Ok(Self::new(steps))
+	}
+}
+
+impl Backend for Scripted {
+	fn button(&mut self) -> Result<Option<Box<dyn Edges>>> {
+		Ok(self.steps.take().map(|steps| {
+			Box::new(ScriptedEdges {
+				level: Level::High,
+				last: Instant::now(),
+				steps,
+			}) as Box<dyn Edges>
+		}))
+	}
+
+	fn leds(&mut self) -> Result<Box<dyn Render + Send>> {
+		Ok(Box::new(PrintStatus(None)))
+	}
+}
+
+impl Edges for ScriptedEdges {
+	fn current(&self) -> Level {
+		self.level
+	}
+
+	fn next(&mut self, timeout: Option<Instant>) -> Result<bool> {
+		let due = self.steps.front().map(|(wait, _)| self.last + *wait);
+		match (due, timeout) {
+			(Some(due), Some(timeout)) if timeout < due => {
+				sleep(timeout.saturating_duration_since(Instant::now()));
+				Ok(false)
+			}
+			(Some(due), _) => {
+				sleep(due.saturating_duration_since(Instant::now()));
+				let (_, level) = self.steps.pop_front().unwrap();
+				self.last = due;
+				self.level = level;
+				Ok(true)
+			}
+			(None, Some(timeout)) => {
+				sleep(timeout.saturating_duration_since(Instant::now()));
+				Ok(false)
+			}
+			(None, None) => loop {
+				// Script is over, nothing will ever happen again
+				park();
+			},
+		}
+	}
+}
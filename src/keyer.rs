@@ -0,0 +1,349 @@
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::cmd::{Morse, MorseWord};
+
+/// Without any press for this long, the text is thrown away
+const IDLE: Duration = Duration::from_secs(30);
+/// Keeps a few stray presses from running the estimate off
+const DOT_MIN: Duration = Duration::from_millis(30);
+const DOT_MAX: Duration = Duration::from_secs(1);
+/// Weight of the latest press in the dot length estimate
+const ADAPT: f32 = 0.25;
+
+/// International Morse code, ITU-R M.1677
+static CODE: &[(char, &str)] = &[
+	('a', ".-"),
+	('b', "-..."),
+	('c', "-.-."),
+	('d', "-.."),
+	('e', "."),
+	('f', "..-."),
+	('g', "--."),
+	('h', "...."),
+	('i', ".."),
+	('j', ".---"),
+	('k', "-.-"),
+	('l', ".-.."),
+	('m', "--"),
+	('n', "-."),
+	('o', "---"),
+	('p', ".--."),
+	('q', "--.-"),
+	('r', ".-."),
+	('s', "..."),
+	('t', "-"),
+	('u', "..-"),
+	('v', "...-"),
+	('w', ".--"),
+	('x', "-..-"),
+	('y', "-.--"),
+	('z', "--.."),
+	('1', ".----"),
+	('2', "..---"),
+	('3', "...--"),
+	('4', "....-"),
+	('5', "....."),
+	('6', "-...."),
+	('7', "--..."),
+	('8', "---.."),
+	('9', "----."),
+	('0', "-----"),
+	('.', ".-.-.-"),
+	(',', "--..--"),
+	('?', "..--.."),
+	('\'', ".----."),
+	('!', "-.-.--"),
+	('/', "-..-."),
+	('(', "-.--."),
+	(')', "-.--.-"),
+	('&', ".-..."),
+	(':', "---..."),
+	(';', "-.-.-."),
+	('=', "-...-"),
+	('+', ".-.-."),
+	('-', "-....-"),
+	('_', "..--.-"),
+	('"', ".-..-."),
+	('@', ".--.-."),
+];
+
+fn decode(letter: &[Morse]) -> Option<char> {
+	let letter = letter
+		.iter()
+		.map(|m| match m {
+			Morse::Short => '.',
+			Morse::Long => '-',
+		})
+		.collect::<String>();
+	CODE.iter()
+		.find(|(_, code)| *code == letter)
+		.map(|(c, _)| *c)
+}
+
+/// Eight or more dots, takes back the last word
+fn is_error(letter: &[Morse]) -> bool {
+	letter.len() >= 8 && letter.iter().all(|m| *m == Morse::Short)
+}
+
+/// Dot length, estimated from the recent presses. A dash counts as three dots.
+/// Shared by the keyer and the adaptive command timing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Dot(Duration);
+
+impl Dot {
+	pub fn new(dot: Duration) -> Self {
+		Dot(dot.clamp(DOT_MIN, DOT_MAX))
+	}
+
+	pub fn get(self) -> Duration {
+		self.0
+	}
+
+	/// Presses shorter than this are dots, halfway between a dot and a dash
+	pub fn threshold(self) -> Duration {
+		self.0 * 2
+	}
+
+	pub fn classify(self, press: Duration) -> Morse {
+		match press < self.threshold() {
+			true => Morse::Short,
+			false => Morse::Long,
+		}
+	}
+
+	pub fn update(&mut self, press: Duration, element: Morse) {
+		let dot = match element {
+			Morse::Short => press,
+			Morse::Long => press / 3,
+		};
+		*self = Dot::new(self.0.mul_f32(1.0 - ADAPT) + dot.mul_f32(ADAPT));
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Keyed {
+	Going,
+	Send(String),
+	GaveUp,
+}
+
+/// Decodes presses into text. Timing follows the usual ratios:
+/// a dash is three dots, letters are three dots apart, words seven.
+pub(crate) struct Keyer {
+	end: MorseWord,
+	dot: Dot,
+	letter: Vec<Morse>,
+	text: String,
+	/// Last release, gaps are measured from here
+	up: Instant,
+}
+
+impl Keyer {
+	pub fn new(dot: Dot, end: MorseWord) -> Self {
+		Keyer {
+			end,
+			dot,
+			letter: Vec::new(),
+			text: String::new(),
+			up: Instant::now(),
+		}
+	}
+
+	/// When the next gap (letter, word, giving up) ends if nothing is pressed
+	pub fn deadline(&self) -> Instant {
+		match (
+			self.letter.is_empty(),
+			self.text.is_empty() || self.text.ends_with(' '),
+		) {
+			(false, _) => self.up + self.dot.get() * 2,
+			(true, false) => self.up + self.dot.get() * 5,
+			(true, true) => self.up + IDLE,
+		}
+	}
+
+	pub fn down(&mut self, at: Instant) -> Keyed {
+		self.gap(at)
+	}
+
+	pub fn up(&mut self, down: Instant, up: Instant) -> Morse {
+		let press = up.saturating_duration_since(down);
+		let element = self.dot.classify(press);
+		self.dot.update(press, element);
+		debug!(?press, ?element, dot = ?self.dot, "Keyed");
+		self.letter.push(element);
+		self.up = up;
+		element
+	}
+
+	/// The estimate after keying, for the command timing to pick up
+	pub fn dot(&self) -> Dot {
+		self.dot
+	}
+
+	/// Nothing was pressed until now
+	pub fn idle(&mut self, now: Instant) -> Keyed {
+		self.gap(now)
+	}
+
+	fn gap(&mut self, until: Instant) -> Keyed {
+		let gap = until.saturating_duration_since(self.up);
+		let dot = self.dot.get();
+		if !self.letter.is_empty() && gap >= dot * 2 {
+			let letter = std::mem::take(&mut self.letter);
+			if letter == self.end.0 {
+				return Keyed::Send(self.text.trim().to_owned());
+			} else if is_error(&letter) {
+				let kept = self.text.trim_end().rfind(' ').map_or(0, |i| i + 1);
+				self.text.truncate(kept);
+			} else if let Some(c) = decode(&letter) {
+				self.text.push(c);
+			} else {
+				debug!(letter = ?MorseWord(letter), "Not a letter");
+			}
+			debug!(text = %self.text, "Keyer");
+		}
+		if gap >= dot * 5 && !self.text.is_empty() && !self.text.ends_with(' ') {
+			self.text.push(' ');
+		}
+		match gap >= IDLE {
+			true => Keyed::GaveUp,
+			false => Keyed::Going,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DOT: Duration = Duration::from_millis(100);
+
+	fn word(code: &str) -> MorseWord {
+		MorseWord::try_from(std::borrow::Cow::from(code)).unwrap()
+	}
+
+	/// The dot estimate moves a little even in perfect rhythm, f32 rounding
+	fn near(a: Instant, b: Instant) -> bool {
+		a.max(b) - a.min(b) < Duration::from_millis(1)
+	}
+
+	/// Keys in perfect rhythm
+	struct Fist {
+		keyer: Keyer,
+		now: Instant,
+		keyed: Vec<Keyed>,
+	}
+
+	impl Fist {
+		fn new() -> Self {
+			let keyer = Keyer::new(Dot::new(DOT), word(".-.-."));
+			Fist {
+				keyer,
+				now: Instant::now(),
+				keyed: vec![],
+			}
+		}
+
+		/// Dots and dashes, a space between letters, a slash between words
+		fn key(&mut self, code: &str) -> &mut Self {
+			for c in code.chars() {
+				match c {
+					'.' | '-' => {
+						let press = if c == '.' { DOT } else { DOT * 3 };
+						self.keyed.push(self.keyer.down(self.now));
+						self.keyer.up(self.now, self.now + press);
+						self.now += press + DOT;
+					}
+					// On top of the dot after each element
+					' ' => self.now += DOT * 2,
+					'/' => self.now += DOT * 6,
+					_ => unreachable!(),
+				}
+			}
+			self
+		}
+
+		fn idle(&mut self, time: Duration) -> Keyed {
+			self.now += time;
+			self.keyer.idle(self.now)
+		}
+
+		fn going(&self) -> bool {
+			self.keyed.iter().all(|k| *k == Keyed::Going)
+		}
+	}
+
+	#[test]
+	fn letters_and_words() {
+		let mut fist = Fist::new();
+		fist.key("... --- .../-- ... .-.-.");
+		assert!(fist.going());
+		assert_eq!(fist.idle(DOT * 2), Keyed::Send("sos ms".to_owned()));
+	}
+
+	#[test]
+	fn gaps() {
+		let mut fist = Fist::new();
+		fist.key(".-");
+		let up = fist.now - DOT;
+		assert!(near(fist.keyer.deadline(), up + DOT * 2));
+		assert_eq!(fist.idle(DOT + DOT / 10), Keyed::Going);
+		assert_eq!(fist.keyer.text, "a");
+		assert!(near(fist.keyer.deadline(), up + DOT * 5));
+		assert_eq!(fist.idle(DOT * 3), Keyed::Going);
+		assert_eq!(fist.keyer.text, "a ");
+		assert_eq!(fist.keyer.deadline(), up + IDLE);
+	}
+
+	#[test]
+	fn error_takes_back_a_word() {
+		let mut fist = Fist::new();
+		fist.key("-.-. .-/-.. --- --./........ .-.-.");
+		assert!(fist.going());
+		assert_eq!(fist.idle(DOT * 2), Keyed::Send("ca".to_owned()));
+
+		// Also right after the word, without a word gap
+		let mut fist = Fist::new();
+		fist.key("-.-. .-/-.. --- --. ........ -.-. .- - .-.-.");
+		assert_eq!(fist.idle(DOT * 2), Keyed::Send("ca cat".to_owned()));
+	}
+
+	#[test]
+	fn end_word_only_as_a_letter() {
+		let mut fist = Fist::new();
+		// .-.-.- is a full stop, not the end word and a dash
+		fist.key("..- .--. .-.-.- .-.-.");
+		assert!(fist.going());
+		assert_eq!(fist.idle(DOT * 2), Keyed::Send("up.".to_owned()));
+	}
+
+	#[test]
+	fn unknown_letters_are_dropped() {
+		let mut fist = Fist::new();
+		fist.key(".. ...--.. .. .-.-.");
+		assert_eq!(fist.idle(DOT * 2), Keyed::Send("ii".to_owned()));
+	}
+
+	#[test]
+	fn gives_up_when_idle() {
+		let mut fist = Fist::new();
+		assert_eq!(fist.idle(IDLE - DOT), Keyed::Going);
+		fist.key(".-");
+		assert_eq!(fist.idle(IDLE - DOT * 2), Keyed::Going);
+		assert_eq!(fist.idle(DOT * 2), Keyed::GaveUp);
+	}
+
+	#[test]
+	fn dot_follows_the_pace() {
+		let mut dot = Dot::new(DOT);
+		for _ in 0..20 {
+			dot.update(DOT * 2, Morse::Short);
+			dot.update(DOT * 6, Morse::Long);
+		}
+		assert!(dot.get().abs_diff(DOT * 2) < Duration::from_millis(5));
+		assert_eq!(dot.classify(DOT * 3), Morse::Short);
+		assert_eq!(dot.classify(DOT * 5), Morse::Long);
+		assert_eq!(Dot::new(Duration::ZERO).get(), DOT_MIN);
+	}
+}
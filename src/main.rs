@@ -1,9 +1,15 @@
 mod audio;
 mod button;
 mod cmd;
+mod config;
+mod dnd;
+mod hw;
+mod keyer;
 pub mod misc;
 mod mtx;
+mod queue;
 mod status;
+mod validate;
 use anyhow::{bail, Context, Result};
 use directories::ProjectDirs;
 use futures::stream::StreamExt;
@@ -14,14 +20,16 @@ use matrix_sdk::{
 	ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId},
 	Client, LoopCtrl, Session,
 };
-use rppal::gpio::Gpio;
 use serde::{Deserialize, Serialize};
 use std::process::exit;
 use std::{
 	fs,
 	sync::{Arc, Mutex},
 };
-use std::{fs::File, path::Path};
+use std::{
+	fs::File,
+	path::{Path, PathBuf},
+};
 use std::{future::Future, str::FromStr};
 use tokio::{
 	signal::unix::{signal, SignalKind},
@@ -88,14 +96,31 @@ structstruck::strike! {
 			#[clap(short = 'f', long)]
 			overwrite: bool,
 		}),
+		/// Verify this device from another session, needed for encrypted rooms
+		Verify,
+		/// Check config.yaml, cmds.yaml and the session, exits non-zero on errors
+		ValidateConfig(pub struct {
+			/// Channels as given to run, to check Room commands against
+			#[clap(short, long)]
+			channel: Vec<OwnedRoomId>,
+		}),
+		/// Run a WAV file through the recording DSP chain from config.yaml
+		Dsp(pub struct {
+			input: PathBuf,
+			output: PathBuf,
+		}),
 		/// Run normally
 		Run(pub struct {
-			/// Join channel (wait for invite if not provided)
+			/// Join channel (wait for invite if not provided).
+			/// May be given several times, the first one is where recordings go by default.
 			#[clap(short, long)]
-			channel: Option<OwnedRoomId>,
-			/// Leave joined channels other than the one specified
+			channel: Vec<OwnedRoomId>,
+			/// Leave joined channels other than the ones specified
 			#[clap(long)]
 			leave: bool,
+			/// Audio backend (default from config.yaml, or pulse/pacat)
+			#[clap(short, long, value_enum)]
+			audio: Option<audio::AudioKind>,
 			/// Hardware
 			#[clap(subcommand)]
 			hardware: enum {
@@ -112,7 +137,12 @@ structstruck::strike! {
 					///  - Green: Playback
 					///  - Turquoise: Waiting on Server
 					///  - Blue: Waiting for playback by other devices
-					///  - Yellow: Connection problem
+					///  - Yellow: Logged out, needs login again
+					///  - Blinking green: Messages held back by do not disturb
+					///  - Alternating blue and green: New message, tap to play (on-demand playback)
+					///  - Blinking white, alternating red and blue: Blink and solid LED step of a command
+					///  - Blue flash: Morse dot or dash recognized
+					///  - Green flash: Command found, red flashes: unknown command
 					///  - White: Idle
 					/// If more than three pins are specified, the remaining pins all become ground
 					#[clap(short = 'l', long, verbatim_doc_comment)]
@@ -123,6 +153,13 @@ structstruck::strike! {
 						ground: Vec<u8>,
 					}>,
 				}),
+				/// No GPIO, button on stdin and status printed to the terminal
+				Keyboard,
+				/// No GPIO, button presses replayed from a script file
+				Scripted(struct {
+					/// Lines like "1s down" or "300ms up", each waiting relative to the previous line
+					script: PathBuf,
+				}),
 			},
 		}),
 	}
@@ -155,32 +192,74 @@ impl FromStr for RGBPins {
 }
 
 #[tracing::instrument(skip(args))]
-async fn run(args: &Run, config_dir: &Path) -> Result<()> {
+async fn run(args: &Run, config_dir: &Path, cache_dir: &Path) -> Result<()> {
 	let ctrl_c = tokio::signal::ctrl_c();
 	let mut term = signal(SignalKind::terminate())?;
-	let gpio = Gpio::new().context("Open GPIO for Pins")?;
-	let _leds = status::init_from_args(&args.hardware, &gpio).context("Status LED init")?;
-	let cmds = cmd::ButtonCommands::load(config_dir)?;
+	let settings = config::Settings::load(config_dir)?;
+	audio::init(args.audio, &settings.audio, &settings.playback).context("Audio init")?;
+	let mut hardware = hw::from_args(&args.hardware).context("Hardware init")?;
+	let _leds = status::init(hardware.leds().context("Status LED init")?);
+	dnd::init(&settings.dnd, settings.playback.on_demand);
+	let queue = queue::Queue::open(&cache_dir.join("queue")).context("Playback queue")?;
+	let sent = queue::Queue::open(&cache_dir.join("sent")).context("Sent messages")?;
+	let history = cmd::History {
+		received: queue.clone(),
+		sent: sent.clone(),
+	};
 	let client = mtx::start(config_dir).await.context("Matrix startup")?;
-	let channel = mtx::channel(args, &client).await.context("Join channel")?;
+	let channels = mtx::channels(args, &client).await.context("Join channel")?;
+	let room_ids = channels
+		.iter()
+		.map(|c| c.room_id().to_owned())
+		.collect::<Vec<_>>();
+	let cmds = Arc::new(cmd::ButtonCommands::load(
+		config_dir,
+		history,
+		room_ids.clone(),
+	)?);
 
-	let incoming = mtx::recv_audio_messages(&client).await;
+	let inbox = mtx::Inbox {
+		queue: queue.clone(),
+		tts: settings.tts.clone(),
+	};
+	mtx::recv_audio_messages(&client, room_ids.clone(), inbox.clone()).await;
+	mtx::remote_leds(&client, room_ids.clone()).await;
+	mtx::dnd_topics(&client, &channels).await;
+	for channel in &channels {
+		if let Err(error) = mtx::catch_up(&client, channel, &inbox).await {
+			warn!(?error, room = %channel.room_id(), "Catch up on missed messages");
+		}
+	}
+	let (markers, markerchannel) = mtx::read_markers(client.clone());
 	let running_cmd = Arc::new(Mutex::new(None));
-	let play = audio::play(incoming, running_cmd.clone());
-	let sync = mtx::sync(&client);
+	let play = audio::play(queue, room_ids, markerchannel, running_cmd.clone());
+	let sync = mtx::sync(&client, config_dir);
 	let expect_caught_up_to = Arc::new(Mutex::new(None));
-	mtx::remote_indicator(channel.clone(), client.clone(), expect_caught_up_to.clone()).await;
-	let (textsender, textchannel) = mtx::oggsender(channel, client.clone(), expect_caught_up_to);
-	let button = match args.hardware {
-		Hardware::Seeed2Mic => Some(17),
-		Hardware::SolderedCustom(SolderedCustom { button, .. }) => button,
-	};
-	let button = button.map(|button| button::read(button, textchannel, cmds, running_cmd, &gpio));
+	mtx::remote_indicator(client.clone(), expect_caught_up_to.clone()).await;
+	let (textsender, textchannel) = mtx::oggsender(
+		channels,
+		expect_caught_up_to,
+		settings.transcribe.clone(),
+		sent,
+	);
+	let button = hardware.button().context("Button init")?;
+	let button = button.map(|button| {
+		button::read(
+			button,
+			settings.morse.clone(),
+			textchannel,
+			cmds.clone(),
+			running_cmd,
+		)
+	});
+	let reload = cmds.watch();
 
 	tokio::select! {
-		() = sync => (),
+		e = sync => e.context("Matrix sync")?,
 		e = play => e.context("Audio player")?,
+		e = markers => e.context("Read markers")?,
 		e = textsender => e.context("Audio sender")?,
+		e = reload => e.context("Command reload")?,
 		e = button.unwrap(), if button.is_some() => e.context("Button")?,
 		_ = ctrl_c => return Ok(()),
 		_ = term.recv() => return Ok(()),
@@ -191,16 +270,22 @@ async fn run(args: &Run, config_dir: &Path) -> Result<()> {
 #[tokio::main]
 async fn main() -> Result<()> {
 	tracing_subscriber::fmt::init();
-	let config_dir = ProjectDirs::from("de", "liftm", env!("CARGO_CRATE_NAME"))
+	let dirs = ProjectDirs::from("de", "liftm", env!("CARGO_CRATE_NAME"))
 		.expect("Can't determine settings directory");
-	let config_dir = config_dir.config_dir();
+	let config_dir = dirs.config_dir();
 	let opts: Opts = clap::Parser::parse();
 	debug!("sup");
 	debug!(cfg=?config_dir, opts=?opts, "init");
 	fs::create_dir_all(config_dir).context("Config dir must exist")?;
 	match &opts {
 		Opts::Login(args) => mtx::login(args, config_dir).await,
-		Opts::Run(args) => run(args, config_dir).await,
+		Opts::Verify => mtx::verify(config_dir).await,
+		Opts::ValidateConfig(args) => validate::run(config_dir, &args.channel),
+		Opts::Dsp(args) => {
+			let settings = config::Settings::load(config_dir)?;
+			audio::dsp_file(&settings.audio.dsp, &args.input, &args.output)
+		}
+		Opts::Run(args) => run(args, config_dir, dirs.cache_dir()).await,
 	}?;
 	exit(0);
 }
@@ -1,32 +1,58 @@
-use crate::{audio::Rec, misc::keep_alive, status::MtxStatus, *};
+use crate::{
+	audio::Rec,
+	config::{Transcribe, TranscribeMode},
+	misc::keep_alive,
+	queue::{self, Queue},
+	status::{Flash, LedPattern, MtxStatus},
+	*,
+};
 use futures::TryStreamExt;
 use matrix_sdk::{
+	attachment::{AttachmentConfig, AttachmentInfo, BaseAudioInfo},
 	config::SyncSettings,
+	encryption::verification::{SasVerification, Verification},
 	instant::SystemTime,
-	room::Room,
+	room::{MessagesOptions, Room},
 	ruma::{
+		api::client::error::ErrorKind,
 		events::{
+			key::verification::{
+				done::ToDeviceKeyVerificationDoneEvent, key::ToDeviceKeyVerificationKeyEvent,
+				request::ToDeviceKeyVerificationRequestEvent,
+				start::ToDeviceKeyVerificationStartEvent,
+			},
+			macros::EventContent,
 			receipt::{Receipt, ReceiptEventContent},
-			room::message::{MessageType, RoomMessageEventContent},
+			room::{
+				message::{
+					MessageType, NoticeMessageEventContent, Relation, RoomMessageEventContent,
+					TextMessageEventContent,
+				},
+				topic::SyncRoomTopicEvent,
+			},
+			AnyMessageLikeEvent, AnyTimelineEvent, MessageLikeEvent, OriginalSyncMessageLikeEvent,
 			SyncEphemeralRoomEvent, SyncMessageLikeEvent,
 		},
-		TransactionId,
+		MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId,
 	},
 };
 use regex::Regex;
 use std::{
+	io::{stdin, stdout, Write},
 	path::Path,
-	sync::{Arc, Mutex},
-};
-use tokio::{
-	sync::oneshot,
-	time::{sleep, sleep_until},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
 };
+use tokio::time::{sleep, sleep_until};
 
-static SESSION_PATH: &str = "session.json";
+pub(crate) static SESSION_PATH: &str = "session.json";
+/// State and crypto store, belongs to the device in the session file
+static STORE_PATH: &str = "store";
 
 #[tracing::instrument]
-async fn create_client(hs: &Url) -> Result<Client> {
+async fn create_client(hs: &Url, config_dir: &Path) -> Result<Client> {
 	let client = Client::builder()
 		.user_agent(&format!(
 			"{}/{}",
@@ -34,7 +60,9 @@ async fn create_client(hs: &Url) -> Result<Client> {
 			env!("CARGO_PKG_VERSION")
 		))
 		.homeserver_url(hs.clone())
-		//.store_config(StoreConfig::new())
+		.sled_store(config_dir.join(STORE_PATH), None)
+		.context("Open store")?
+		.handle_refresh_tokens()
 		.build()
 		.await
 		.context("Build client")?;
@@ -58,7 +86,13 @@ pub async fn login(args: &Login, config_dir: &Path) -> Result<()> {
 			pw.as_str()
 		}
 	};
-	let client = create_client(&args.hs).await?;
+	let store_path = config_dir.join(STORE_PATH);
+	if store_path.exists() {
+		// The crypto store can't be reused for a new device
+		info!(?store_path, "Removing store of previous session");
+		fs::remove_dir_all(&store_path).context("Remove old store")?;
+	}
+	let client = create_client(&args.hs, config_dir).await?;
 	let devname = format!(
 		"{} on {}",
 		env!("CARGO_CRATE_NAME"),
@@ -67,6 +101,7 @@ pub async fn login(args: &Login, config_dir: &Path) -> Result<()> {
 	let login = client
 		.login_username(&args.user, pw)
 		.initial_device_display_name(&devname)
+		.request_refresh_token()
 		.send()
 		.await
 		.context("Login")?;
@@ -78,6 +113,15 @@ pub async fn login(args: &Login, config_dir: &Path) -> Result<()> {
 		refresh_token: login.refresh_token,
 	};
 	info!(?session, "logged in");
+	write_session(config_dir, &session)?;
+	debug!(?session_path, "success");
+	Ok(())
+}
+
+/// Goes through a temporary file, so a crash never leaves a truncated session behind
+fn write_session(config_dir: &Path, session: &SessionData) -> Result<()> {
+	let session_path = config_dir.join(SESSION_PATH);
+	let tmp_path = session_path.with_extension("json.tmp");
 	let mut file = fs::OpenOptions::new();
 	file.write(true).truncate(true).create(true);
 	#[cfg(unix)]
@@ -90,24 +134,34 @@ pub async fn login(args: &Login, config_dir: &Path) -> Result<()> {
 		session = *session_path,
 		"Access token may be world readable"
 	);
-	let file = file.open(&*session_path).context("Open session file")?;
-	serde_json::to_writer_pretty(&file, &session).context("Write session file")?;
-	debug!(?file, "success");
+	let file = file.open(&tmp_path).context("Open session file")?;
+	serde_json::to_writer_pretty(&file, session).context("Write session file")?;
+	file.sync_all().context("Write session file")?;
+	fs::rename(&tmp_path, &session_path).context("Replace session file")?;
 	Ok(())
 }
 
-#[tracing::instrument]
-pub async fn start(config_dir: &Path) -> Result<Client> {
+fn read_session(config_dir: &Path) -> Result<SessionData> {
 	let session_path = config_dir.join(SESSION_PATH);
 	anyhow::ensure!(
 		session_path.exists(),
 		"Session data does not exist, please login first."
 	);
 	let sess = File::open(session_path).context("Open session data")?;
-	let sess: SessionData = serde_json::from_reader(sess).context("Read session data")?;
-	let client = create_client(&sess.homeserver).await?;
+	serde_json::from_reader(sess).context("Read session data")
+}
+
+async fn restore(config_dir: &Path) -> Result<Client> {
+	let sess = read_session(config_dir)?;
+	let client = create_client(&sess.homeserver, config_dir).await?;
 	client.restore_login(sess.into()).await?;
 	debug!(woami=?client.whoami().await, "logged in");
+	Ok(client)
+}
+
+#[tracing::instrument]
+pub async fn start(config_dir: &Path) -> Result<Client> {
+	let client = restore(config_dir).await?;
 	status::mtx(MtxStatus::Starting);
 	let sync = client
 		.sync_once(SyncSettings::default())
@@ -119,35 +173,157 @@ pub async fn start(config_dir: &Path) -> Result<Client> {
 	Ok(client)
 }
 
+/// Accept emoji (SAS) verification requests from other sessions, confirming on the terminal
+#[tracing::instrument]
+pub async fn verify(config_dir: &Path) -> Result<()> {
+	let client = restore(config_dir).await?;
+	let done = Arc::new(AtomicBool::new(false));
+	client.add_event_handler(
+		|ev: ToDeviceKeyVerificationRequestEvent, client: Client| async move {
+			let request = client
+				.encryption()
+				.get_verification_request(&ev.sender, &ev.content.transaction_id)
+				.await;
+			match request {
+				Some(request) => {
+					println!("Verification request from {}", ev.sender);
+					if let Err(error) = request.accept().await {
+						error!(?error, "Can't accept verification request");
+					}
+				}
+				None => warn!(?ev, "Unknown verification request"),
+			}
+		},
+	);
+	client.add_event_handler(
+		|ev: ToDeviceKeyVerificationStartEvent, client: Client| async move {
+			if let Some(Verification::SasV1(sas)) = client
+				.encryption()
+				.get_verification(&ev.sender, ev.content.transaction_id.as_str())
+				.await
+			{
+				if let Err(error) = sas.accept().await {
+					error!(?error, "Can't accept SAS verification");
+				}
+			}
+		},
+	);
+	client.add_event_handler(
+		|ev: ToDeviceKeyVerificationKeyEvent, client: Client| async move {
+			if let Some(Verification::SasV1(sas)) = client
+				.encryption()
+				.get_verification(&ev.sender, ev.content.transaction_id.as_str())
+				.await
+			{
+				tokio::spawn(confirm_emoji(sas));
+			}
+		},
+	);
+	client.add_event_handler({
+		let done = done.clone();
+		move |ev: ToDeviceKeyVerificationDoneEvent, client: Client| {
+			let done = done.clone();
+			async move {
+				if let Some(Verification::SasV1(sas)) = client
+					.encryption()
+					.get_verification(&ev.sender, ev.content.transaction_id.as_str())
+					.await
+				{
+					if sas.is_done() {
+						let device = sas.other_device();
+						println!(
+							"Verified device {} of {}",
+							device.device_id(),
+							device.user_id()
+						);
+						done.store(true, Ordering::Relaxed);
+					}
+				}
+			}
+		}
+	});
+	println!("Waiting for a verification request, start one from another session");
+	client
+		.sync_with_callback(SyncSettings::default(), |_| {
+			let done = done.load(Ordering::Relaxed);
+			async move {
+				match done {
+					true => LoopCtrl::Break,
+					false => LoopCtrl::Continue,
+				}
+			}
+		})
+		.await
+		.context("Sync")?;
+	Ok(())
+}
+
+async fn confirm_emoji(sas: SasVerification) {
+	let emoji = match sas.emoji() {
+		Some(emoji) => emoji,
+		None => {
+			warn!("Only emoji verification is supported");
+			sas.cancel().await.ok();
+			return;
+		}
+	};
+	for e in emoji.iter() {
+		println!("  {}  {}", e.symbol, e.description);
+	}
+	print!("Do the emoji match? [yes/no] ");
+	stdout().flush().ok();
+	let answer = tokio::task::spawn_blocking(|| {
+		let mut answer = String::new();
+		stdin().read_line(&mut answer).map(|_| answer)
+	})
+	.await;
+	let res = match answer {
+		Ok(Ok(answer)) if answer.trim().eq_ignore_ascii_case("yes") => sas.confirm().await,
+		_ => {
+			println!("Cancelling verification");
+			sas.cancel().await
+		}
+	};
+	if let Err(error) = res {
+		error!(?error, "Verification failed");
+	}
+}
+
 #[tracing::instrument(skip(client))]
-pub async fn channel(args: &Run, client: &Client) -> Result<JoinedRoom> {
+pub async fn channels(args: &Run, client: &Client) -> Result<Vec<JoinedRoom>> {
 	let chanlist = client.joined_rooms();
 	let scl = chanlist
 		.iter()
 		.map(|c| c.name().unwrap_or_else(|| c.room_id().as_str().to_string()))
 		.collect::<Vec<_>>();
 	debug!(chanlist=?scl);
-	let id: OwnedRoomId = match &args.channel {
-		Some(channel) => {
-			client
-				.join_room_by_id(channel)
-				.await
-				.context("Join as specified")?;
-			if args.leave {
-				futures::stream::iter(chanlist.into_iter().filter(|r| r.room_id() != channel))
-					.map(|r| async move { r.leave().await })
-					.buffer_unordered(5)
-					.try_collect::<()>()
+	let ids: Vec<OwnedRoomId> = match &args.channel[..] {
+		channels @ [_, ..] => {
+			for channel in channels {
+				client
+					.join_room_by_id(channel)
 					.await
-					.context("Leaving superfluous channel")?;
+					.with_context(|| format!("Join {channel} as specified"))?;
+			}
+			if args.leave {
+				futures::stream::iter(
+					chanlist
+						.into_iter()
+						.filter(|r| !channels.iter().any(|c| r.room_id() == &**c)),
+				)
+				.map(|r| async move { r.leave().await })
+				.buffer_unordered(5)
+				.try_collect::<()>()
+				.await
+				.context("Leaving superfluous channel")?;
 			}
-			channel.clone()
+			channels.to_vec()
 		}
-		None => match &chanlist[..] {
-			[chan] => chan.room_id().to_owned(),
+		[] => match &chanlist[..] {
+			[chan] => vec![chan.room_id().to_owned()],
 			[_, ..] => {
 				anyhow::bail!(
-					"Joined more than one channel: {}. (Specify channel parameter)",
+					"Joined more than one channel: {}. (Specify channel parameter, possibly several times)",
 					scl.join(" ")
 				);
 			}
@@ -181,7 +357,7 @@ pub async fn channel(args: &Run, client: &Client) -> Result<JoinedRoom> {
 						})
 						.await
 						.context("Invitation accept sync")?;
-					invitation.room_id().to_owned()
+					vec![invitation.room_id().to_owned()]
 				}
 				invs @ [_, ..] => {
 					error!(invitations = ?invs
@@ -203,26 +379,25 @@ pub async fn channel(args: &Run, client: &Client) -> Result<JoinedRoom> {
 			},
 		},
 	};
-	let c = client
-		.get_joined_room(&id)
-		.context("Not joined to a room")?;
-
-	Ok(c)
+	ids.iter()
+		.map(|id| {
+			client
+				.get_joined_room(id)
+				.with_context(|| format!("Not joined to {id}"))
+		})
+		.collect()
 }
 
-#[tracing::instrument(skip(client, room))]
-pub async fn remote_indicator(
-	room: JoinedRoom,
-	client: Client,
-	expect_caught_up_to: Arc<Mutex<Option<SystemTime>>>,
-) {
-	let here = room.room_id().to_owned();
+/// Expect others to have read up to this time in the room we last sent to
+pub type CaughtUp = Arc<Mutex<Option<(OwnedRoomId, SystemTime)>>>;
+
+#[tracing::instrument(skip(client))]
+pub async fn remote_indicator(client: Client, expect_caught_up_to: CaughtUp) {
 	client.add_event_handler(
 		move |ev: SyncEphemeralRoomEvent<ReceiptEventContent>, room: Room, client: Client| {
-			let here = here.clone();
-			let ecu = *expect_caught_up_to.lock().unwrap();
+			let ecu = expect_caught_up_to.lock().unwrap().clone();
 			async move {
-				let ecu = match ecu {
+				let (here, ecu) = match ecu {
 					Some(ecu) => ecu,
 					None => return,
 				};
@@ -283,117 +458,490 @@ pub async fn remote_indicator(
 	);
 }
 
-#[tracing::instrument(skip(client))]
+/// What goes out to a room
+#[derive(Debug)]
+pub enum Outgoing {
+	Audio(Rec),
+	/// Fixed text, from a command
+	Text {
+		body: String,
+		room: Option<OwnedRoomId>,
+	},
+	/// LED pattern from a command, for the other devices
+	Led {
+		pattern: LedPattern,
+		time: Duration,
+		room: Option<OwnedRoomId>,
+	},
+}
+
+/// Shown on every device in the room for a while, see remote_leds
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "de.liftm.gegensprech.led", kind = MessageLike)]
+pub struct LedEventContent {
+	pub pattern: LedPattern,
+	pub millis: u64,
+}
+
+impl From<Rec> for Outgoing {
+	fn from(rec: Rec) -> Self {
+		Outgoing::Audio(rec)
+	}
+}
+
+/// Until the others' read receipts are past what's about to be sent, see remote_indicator
+fn expect_read(expect_caught_up_to: &CaughtUp, room: &JoinedRoom) {
+	status::caughtup(false);
+	*expect_caught_up_to.lock().unwrap() = Some((room.room_id().to_owned(), SystemTime::now()));
+}
+
+/// Sends to the room a message asks for, or the first room
+#[tracing::instrument(skip(rooms, history))]
 pub fn oggsender(
-	room: JoinedRoom,
-	client: Client,
-	expect_caught_up_to: Arc<Mutex<Option<SystemTime>>>,
-) -> (impl Future<Output = Result<()>>, mpsc::Sender<Rec>) {
-	let (tx, mut rx) = mpsc::channel::<Rec>(4);
+	rooms: Vec<JoinedRoom>,
+	expect_caught_up_to: CaughtUp,
+	transcribe: Option<Transcribe>,
+	history: Arc<Queue>,
+) -> (impl Future<Output = Result<()>>, mpsc::Sender<Outgoing>) {
+	let (tx, mut rx) = mpsc::channel::<Outgoing>(4);
 
 	let process = async move {
-		use matrix_sdk::ruma::events::{
-			room::message::{AudioMessageEventContent, MessageType},
-			AnyMessageLikeEventContent,
-		};
-
 		loop {
-			let Rec { data, info } = rx.recv().await.context("recorder sender")?;
+			let outgoing = rx.recv().await.context("recorder sender")?;
+			let target = match &outgoing {
+				Outgoing::Audio(rec) => &rec.room,
+				Outgoing::Text { room, .. } | Outgoing::Led { room, .. } => room,
+			};
+			let room = match target {
+				Some(id) => match rooms.iter().find(|r| r.room_id() == id) {
+					Some(room) => room,
+					None => {
+						// Not lost, but not where it was meant to go either
+						warn!(?id, "Room isn't configured, sending to the default one");
+						status::flash(Flash::Unknown);
+						audio::cue(Flash::Unknown);
+						&rooms[0]
+					}
+				},
+				None => &rooms[0],
+			};
+			let Rec {
+				data, info, pcm, ..
+			} = match outgoing {
+				Outgoing::Audio(rec) => rec,
+				Outgoing::Text { body, .. } => {
+					let _sending_status = status::send();
+					expect_read(&expect_caught_up_to, room);
+					room.send(RoomMessageEventContent::text_plain(body), None)
+						.await
+						.context("Send text")?;
+					continue;
+				}
+				Outgoing::Led { pattern, time, .. } => {
+					let millis = time.as_millis() as u64;
+					if let Err(error) = room.send(LedEventContent { pattern, millis }, None).await {
+						warn!(?error, "Couldn't send LED pattern");
+					}
+					continue;
+				}
+			};
 			let _sending_status = status::send();
-			let data = client
-				.media()
-				.upload(
-					&info
-						.mimetype
-						.as_deref()
-						.unwrap_or("application/octet-stream")
-						.parse()?,
+			let transcription = transcribe.clone().zip(pcm).map(|(transcribe, pcm)| {
+				let task =
+					tokio::task::spawn_blocking(move || audio::transcribe(&transcribe.cmd, &pcm));
+				async move {
+					match task.await.map_err(anyhow::Error::from).and_then(|r| r) {
+						Ok(text) if !text.is_empty() => Some(text),
+						Ok(_) => None,
+						Err(error) => {
+							warn!(?error, "Transcription failed");
+							None
+						}
+					}
+				}
+			});
+			let mode = transcribe.as_ref().map(|t| t.mode);
+			let (body, follow_up) = match (mode, transcription) {
+				(Some(TranscribeMode::Body), Some(transcription)) => (transcription.await, None),
+				(_, transcription) => (None, transcription),
+			};
+			let mimetype = info
+				.mimetype
+				.as_deref()
+				.unwrap_or("application/octet-stream")
+				.parse()?;
+			// Takes care of encrypting the file if the room is encrypted
+			let config = AttachmentConfig::new().info(AttachmentInfo::Audio(BaseAudioInfo {
+				duration: info.duration,
+				size: info.size,
+			}));
+
+			expect_read(&expect_caught_up_to, room);
+			let sent = room
+				.send_attachment(
+					body.as_deref().unwrap_or("Aufnahme"),
+					&mimetype,
 					&data,
+					config,
 				)
-				.await?;
-
-			let content = AnyMessageLikeEventContent::RoomMessage(RoomMessageEventContent::new(
-				MessageType::Audio(AudioMessageEventContent::plain(
-					"Aufnahme".to_owned(),
-					data.content_uri,
-					Some(info.into()),
-				)),
-			));
-
-			let txn_id = TransactionId::new();
-			status::caughtup(false);
-			*expect_caught_up_to.lock().unwrap() = Some(SystemTime::now());
-			room.send(content, Some(&txn_id)).await.unwrap();
+				.await
+				.context("Send recording")?;
+			let remembered = history.remember(
+				sent.event_id.clone(),
+				room.room_id().to_owned(),
+				room.own_user_id().to_owned(),
+				MilliSecondsSinceUnixEpoch::now(),
+				info.mimetype.clone(),
+				&data,
+			);
+			if let Err(error) = remembered {
+				warn!(?error, "Can't keep sent recording for replay");
+			}
+			if let Some(follow_up) = follow_up {
+				let room = room.clone();
+				tokio::spawn(async move {
+					let text = match follow_up.await {
+						Some(text) => text,
+						None => return,
+					};
+					let content = serde_json::json!({
+						"msgtype": "m.text",
+						"body": text,
+						"m.relates_to": {
+							"rel_type": "m.thread",
+							"event_id": sent.event_id,
+							"is_falling_back": true,
+							"m.in_reply_to": { "event_id": sent.event_id },
+						},
+					});
+					if let Err(error) = room.send_raw(content, "m.room.message", None).await {
+						warn!(?error, "Failed to send transcription");
+					}
+				});
+			}
 		}
 	};
 	keep_alive(&tx); // Dumb if we exit due to an error elsewhere that'll take us down anyway
 	(process, tx)
 }
 
-pub async fn recv_audio_messages(
-	client: &Client,
-) -> mpsc::Receiver<(Vec<u8>, Option<String>, oneshot::Sender<()>)> {
-	let (tx, rx) = mpsc::channel(4);
+/// Where incoming messages go
+#[derive(Clone)]
+pub struct Inbox {
+	pub queue: Arc<Queue>,
+	/// Command that speaks text messages, reading text on stdin and writing WAV to stdout
+	pub tts: Option<Vec<String>>,
+}
+
+#[tracing::instrument(skip(client, inbox))]
+pub async fn recv_audio_messages(client: &Client, rooms: Vec<OwnedRoomId>, inbox: Inbox) {
 	client.add_event_handler(
 		move |ev: SyncMessageLikeEvent<RoomMessageEventContent>, room: Room, client: Client| {
-			let tx = tx.clone();
+			let inbox = inbox.clone();
+			let listening = rooms.iter().any(|r| &**r == room.room_id());
 			async move {
 				debug!(?ev, "received");
-				if Some(ev.sender()) == client.user_id() {
+				if Some(ev.sender()) == client.user_id() || !listening {
 					return;
 				}
 				let ev = match ev.as_original() {
 					Some(ev) => ev.to_owned(),
 					None => return,
 				};
-				let eid = ev.event_id;
-				if let MessageType::Audio(amc) = ev.content.msgtype {
-					info!(?amc, "received audio");
-					let mtyp = amc
-						.info
-						.as_ref()
-						.and_then(|info| info.mimetype.as_ref())
-						.cloned();
-					let data = client.media().get_file(amc, false).await.expect("dl");
-					if let Some(data) = data {
-						let (play, played) = oneshot::channel();
-						tx.send((data, mtyp, play)).await.expect("pipesend");
-						tokio::spawn(async move {
-							let res = (move || async move {
-								let room = match room {
-									Room::Joined(j) => Some(j),
-									_ => None,
-								}
-								.context("Room not joined")?;
-								let x = played.await;
-								x.context("Not played")?;
-								room.read_marker(&eid, Some(&eid))
-									.await
-									.context("Marker request error")?;
-								Result::<_, anyhow::Error>::Ok(())
-							})()
-							.await;
-							if let Err(e) = res {
-								warn!(?e, "Didn't set read marker")
-							}
-						});
-					} else {
-						warn!("audio event, no data file");
-					}
+				let res = enqueue(
+					&client,
+					&inbox,
+					room.room_id(),
+					ev.event_id,
+					ev.sender,
+					ev.origin_server_ts,
+					ev.content,
+				)
+				.await;
+				if let Err(e) = res {
+					warn!(?e, "Couldn't queue message");
+				}
+			}
+		},
+	);
+}
+
+async fn enqueue(
+	client: &Client,
+	inbox: &Inbox,
+	room: &RoomId,
+	eid: OwnedEventId,
+	sender: OwnedUserId,
+	ts: MilliSecondsSinceUnixEpoch,
+	content: RoomMessageEventContent,
+) -> Result<()> {
+	let queue = &inbox.queue;
+	if queue.knows(room, &eid, ts) {
+		debug!(?eid, "already queued");
+		return Ok(());
+	}
+	match content.msgtype {
+		MessageType::Audio(amc) => {
+			info!(?amc, "received audio");
+			let mtyp = amc
+				.info
+				.as_ref()
+				.and_then(|info| info.mimetype.as_ref())
+				.cloned();
+			let data = client
+				.media()
+				.get_file(amc, false)
+				.await
+				.context("Download audio")?
+				.context("audio event, no data file")?;
+			queue.push(eid, room.to_owned(), sender, ts, mtyp, &data)?;
+		}
+		MessageType::Text(TextMessageEventContent { body, .. })
+		| MessageType::Notice(NoticeMessageEventContent { body, .. }) => {
+			let tts = match &inbox.tts {
+				Some(tts) => tts.clone(),
+				None => return Ok(()),
+			};
+			// Transcriptions are threaded replies to the recording, which ruma 0.7 parses as plain replies
+			if let Some(Relation::Reply { in_reply_to }) = &content.relates_to {
+				if queue.sender(&in_reply_to.event_id).as_ref() == Some(&sender) {
+					debug!(?eid, "not speaking transcription of a recording");
+					return Ok(());
+				}
+			}
+			info!(?body, "received text");
+			let wav = tokio::task::spawn_blocking(move || audio::speak(&tts, &body))
+				.await
+				.context("TTS spawn")??;
+			queue.push(
+				eid,
+				room.to_owned(),
+				sender,
+				ts,
+				Some("audio/wav".to_owned()),
+				&wav,
+			)?;
+		}
+		_ => (),
+	};
+	Ok(())
+}
+
+/// Do not disturb for as long as a room's topic has a line like "gegensprech-dnd: on"
+#[tracing::instrument(skip(client, rooms))]
+/// Shows the LED patterns other devices send with commands, for what's left of their time
+pub async fn remote_leds(client: &Client, rooms: Vec<OwnedRoomId>) {
+	client.add_event_handler(
+		move |ev: OriginalSyncMessageLikeEvent<LedEventContent>, room: Room, client: Client| {
+			let listening = rooms.iter().any(|r| &**r == room.room_id());
+			async move {
+				if Some(&*ev.sender) == client.user_id() || !listening {
+					return;
+				}
+				// Old ones, e.g. from the first sync, have run out already
+				let sent = ev
+					.origin_server_ts
+					.to_system_time()
+					.unwrap_or(SystemTime::UNIX_EPOCH);
+				let age = SystemTime::now().duration_since(sent).unwrap_or_default();
+				let Some(left) = Duration::from_millis(ev.content.millis).checked_sub(age) else {
+					return;
 				};
+				debug!(?ev.content, ?left, "LED pattern from the room");
+				// Not holding up the other event handlers
+				tokio::spawn(async move {
+					let _pattern = status::pattern(ev.content.pattern);
+					sleep(left).await;
+				});
 			}
 		},
 	);
-	rx
+}
+
+pub async fn dnd_topics(client: &Client, rooms: &[JoinedRoom]) {
+	for room in rooms {
+		topic_dnd(room.room_id(), room.topic().as_deref());
+	}
+	let rooms = rooms
+		.iter()
+		.map(|r| r.room_id().to_owned())
+		.collect::<Vec<_>>();
+	client.add_event_handler(move |ev: SyncRoomTopicEvent, room: Room| {
+		let listening = rooms.iter().any(|r| &**r == room.room_id());
+		async move {
+			if let (true, Some(ev)) = (listening, ev.as_original()) {
+				topic_dnd(room.room_id(), Some(&ev.content.topic));
+			}
+		}
+	});
+}
+
+fn topic_dnd(room: &RoomId, topic: Option<&str>) {
+	let on = topic
+		.into_iter()
+		.flat_map(str::lines)
+		.filter_map(|line| line.trim().strip_prefix(dnd::TOPIC_PREFIX))
+		.last()
+		.map_or(false, |v| matches!(v.trim(), "on" | "yes" | "true"));
+	dnd::set_room(room.to_owned(), on);
+}
+
+/// Queue whatever arrived after our last read receipt while we weren't running
+#[tracing::instrument(skip(client, room, inbox))]
+pub async fn catch_up(client: &Client, room: &JoinedRoom, inbox: &Inbox) -> Result<()> {
+	let me = client.user_id().context("Not logged in")?;
+	let last = match room
+		.user_read_receipt(me)
+		.await
+		.context("Own read receipt")?
+	{
+		Some((last, _)) => last,
+		None => {
+			info!("No read receipt of our own, nothing to catch up on");
+			return Ok(());
+		}
+	};
+	let mut missed = vec![];
+	let mut from = None::<String>;
+	'pages: for _ in 0..3 {
+		let mut options = MessagesOptions::backward();
+		options.from = from.as_deref();
+		let page = room
+			.messages(options)
+			.await
+			.context("Fetch missed messages")?;
+		for ev in page.chunk {
+			let ev = match ev.event.deserialize() {
+				Ok(ev) => ev,
+				Err(error) => {
+					warn!(?error, "Undecodable event");
+					continue;
+				}
+			};
+			if ev.event_id() == &*last {
+				break 'pages;
+			}
+			if let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+				MessageLikeEvent::Original(ev),
+			)) = ev
+			{
+				if &*ev.sender != me {
+					missed.push(ev);
+				}
+			}
+		}
+		from = match page.end {
+			Some(end) => Some(end),
+			None => break,
+		};
+	}
+	info!(missed = missed.len(), "catching up");
+	for ev in missed.into_iter().rev() {
+		let eid = ev.event_id.clone();
+		if let Err(error) = enqueue(
+			client,
+			inbox,
+			room.room_id(),
+			ev.event_id,
+			ev.sender,
+			ev.origin_server_ts,
+			ev.content,
+		)
+		.await
+		{
+			warn!(?error, ?eid, "Can't queue missed message");
+		}
+	}
+	Ok(())
+}
+
+#[tracing::instrument(skip(client))]
+pub fn read_markers(
+	client: Client,
+) -> (impl Future<Output = Result<()>>, mpsc::Sender<queue::Entry>) {
+	let (tx, mut rx) = mpsc::channel::<queue::Entry>(4);
+	let process = async move {
+		loop {
+			let entry = rx.recv().await.context("read marker sender")?;
+			let res = async {
+				let room = client
+					.get_joined_room(&entry.room_id)
+					.context("Room not joined")?;
+				// A skipped message only moves our fully read marker,
+				// the public receipt stays so others see it wasn't heard
+				let receipt = match entry.state {
+					queue::State::Played => Some(&*entry.event_id),
+					queue::State::Skipped => None,
+					queue::State::Pending | queue::State::Failed => return Ok(()),
+				};
+				room.read_marker(&entry.event_id, receipt)
+					.await
+					.context("Marker request error")?;
+				anyhow::Ok(())
+			}
+			.await;
+			if let Err(e) = res {
+				warn!(?e, event = ?entry.event_id, "Didn't set read marker")
+			}
+		}
+	};
+	keep_alive(&tx);
+	(process, tx)
+}
+
+/// What the homeserver complained about, if it did
+fn client_api_error_kind(error: &matrix_sdk::Error) -> Option<&ErrorKind> {
+	use matrix_sdk::{
+		ruma::api::error::{FromHttpResponseError, ServerError},
+		HttpError, RefreshTokenError, RumaApiError,
+	};
+	match error {
+		matrix_sdk::Error::Http(HttpError::Api(FromHttpResponseError::Server(
+			ServerError::Known(RumaApiError::ClientApi(error)),
+		)))
+		| matrix_sdk::Error::Http(HttpError::RefreshToken(RefreshTokenError::ClientApi(error))) => {
+			Some(&error.kind)
+		}
+		_ => None,
+	}
+}
+
+fn missing_refresh_token(error: &matrix_sdk::Error) -> bool {
+	use matrix_sdk::{HttpError, RefreshTokenError};
+	matches!(
+		error,
+		matrix_sdk::Error::Http(HttpError::RefreshToken(
+			RefreshTokenError::RefreshTokenRequired
+		))
+	)
+}
+
+fn session_tokens(client: &Client) -> Option<(String, Option<String>)> {
+	client
+		.session()
+		.map(|session| (session.access_token, session.refresh_token))
+}
+
+/// The homeserver doesn't want us anymore. Wait until someone logs in again, then exit for a restart.
+async fn logged_out(config_dir: &Path) -> Result<()> {
+	error!("Logged out, please log in again");
+	status::mtx(MtxStatus::LoggedOut);
+	let session_path = config_dir.join(SESSION_PATH);
+	let modified = || fs::metadata(&session_path).and_then(|m| m.modified()).ok();
+	let before = modified();
+	while modified() == before {
+		sleep(Duration::from_secs(10)).await;
+	}
+	anyhow::bail!("Session file replaced, restart to use it");
 }
 
 #[tracing::instrument]
-pub async fn sync(client: &Client) {
+pub async fn sync(client: &Client, config_dir: &Path) -> Result<()> {
 	let sto = Duration::from_secs(60);
-	let ss = SyncSettings::new().timeout(sto);
+	let mut ss = SyncSettings::new().timeout(sto);
 	let last_sync = Arc::new(Mutex::new(Instant::now()));
 	let last_sync_read = last_sync.clone();
-	// sync_once doesn't fail because I haven't set a RequestConfig::retry_limit.
+	// sync_once doesn't fail on network errors because I haven't set a RequestConfig::retry_limit.
 	// Setting one has wider implications, so I'll just check the last sync time regularly.
 
 	tokio::spawn(async move {
@@ -409,11 +957,51 @@ pub async fn sync(client: &Client) {
 		}
 	});
 
-	let () = client
-		.sync_with_callback(ss, move |_| {
-			*last_sync.lock().unwrap() = Instant::now();
-			async { LoopCtrl::Continue }
-		})
-		.await
-		.expect("infinite loop finished");
+	let mut tokens = session_tokens(client);
+	loop {
+		match client.sync_once(ss.clone()).await {
+			Ok(response) => {
+				*last_sync.lock().unwrap() = Instant::now();
+				ss = ss.token(response.next_batch);
+			}
+			Err(error) if missing_refresh_token(&error) => return logged_out(config_dir).await,
+			Err(error) => match client_api_error_kind(&error) {
+				Some(ErrorKind::UnknownToken { soft_logout }) => {
+					// Usually handled by the client already, but it doesn't hurt to try again
+					warn!(soft_logout, "Access token rejected, refreshing");
+					// Ok(None) means another refresh got there first
+					if let Err(error) = client.refresh_access_token().await {
+						let error = matrix_sdk::Error::from(error);
+						let rejected = matches!(
+							client_api_error_kind(&error),
+							Some(ErrorKind::UnknownToken { .. })
+						);
+						if rejected || missing_refresh_token(&error) {
+							warn!(?error, "Can't refresh the access token");
+							return logged_out(config_dir).await;
+						}
+						warn!(?error, "Token refresh failed, retrying");
+						sleep(Duration::from_secs(5)).await;
+					} else {
+						info!("Access token refreshed");
+					}
+				}
+				_ => {
+					warn!(?error, "Sync failed");
+					sleep(Duration::from_secs(5)).await;
+				}
+			},
+		}
+		let current = session_tokens(client);
+		if current != tokens {
+			info!("Access token changed, saving");
+			let mut session = read_session(config_dir)?;
+			if let Some((access_token, refresh_token)) = current.clone() {
+				session.access_token = access_token;
+				session.refresh_token = refresh_token;
+			}
+			write_session(config_dir, &session)?;
+			tokens = current;
+		}
+	}
 }
@@ -0,0 +1,302 @@
+use anyhow::{Context, Result};
+use matrix_sdk::ruma::{
+	EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+use crate::audio::Playback;
+
+static INDEX: &str = "queue.json";
+/// Finished messages whose media is kept around
+const KEEP_DONE: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+	Pending,
+	Played,
+	Skipped,
+	Failed,
+}
+
+impl From<Playback> for State {
+	fn from(playback: Playback) -> Self {
+		match playback {
+			Playback::Played => State::Played,
+			Playback::Skipped => State::Skipped,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+	pub event_id: OwnedEventId,
+	pub room_id: OwnedRoomId,
+	pub sender: OwnedUserId,
+	pub ts: MilliSecondsSinceUnixEpoch,
+	pub mimetype: Option<String>,
+	/// Media file name, relative to the queue directory
+	file: String,
+	pub state: State,
+}
+
+/// What goes into the index file
+#[derive(Serialize, Deserialize, Default)]
+struct Index {
+	entries: Vec<Entry>,
+	/// Per room, the newest message that was pruned from the entries.
+	/// Skipped or failed messages don't move the read receipt,
+	/// so without this they'd come back with the next catch up.
+	#[serde(default)]
+	pruned: BTreeMap<OwnedRoomId, MilliSecondsSinceUnixEpoch>,
+}
+
+/// Incoming messages, persisted so nothing gets lost or played twice across restarts
+pub struct Queue {
+	dir: PathBuf,
+	index: Mutex<Index>,
+	notify: Notify,
+}
+
+impl Queue {
+	#[tracing::instrument]
+	pub fn open(dir: &Path) -> Result<Arc<Queue>> {
+		fs::create_dir_all(dir).context("Create queue dir")?;
+		let index = dir.join(INDEX);
+		let index: Index = match index.exists() {
+			true => serde_json::from_slice(&fs::read(&index).context("Read queue index")?)
+				.context("Parse queue index")?,
+			false => Index::default(),
+		};
+		debug!(
+			pending = index
+				.entries
+				.iter()
+				.filter(|e| e.state == State::Pending)
+				.count(),
+			total = index.entries.len(),
+			"queue loaded"
+		);
+		let queue = Queue {
+			dir: dir.to_owned(),
+			index: Mutex::new(index),
+			notify: Notify::new(),
+		};
+		queue.notify.notify_one();
+		Ok(Arc::new(queue))
+	}
+
+	/// Whether a message was queued before, or is older than what was already pruned
+	pub fn knows(
+		&self,
+		room_id: &RoomId,
+		event_id: &EventId,
+		ts: MilliSecondsSinceUnixEpoch,
+	) -> bool {
+		let index = self.index.lock().unwrap();
+		index
+			.pruned
+			.get(room_id)
+			.map_or(false, |&pruned| ts <= pruned)
+			|| index.entries.iter().any(|e| e.event_id == event_id)
+	}
+
+	/// Who sent a message we still have
+	pub fn sender(&self, event_id: &EventId) -> Option<OwnedUserId> {
+		let index = self.index.lock().unwrap();
+		index
+			.entries
+			.iter()
+			.find(|e| e.event_id == event_id)
+			.map(|e| e.sender.clone())
+	}
+
+	/// Returns false if the event was already queued (or played) before
+	#[tracing::instrument(skip(self, data))]
+	pub fn push(
+		&self,
+		event_id: OwnedEventId,
+		room_id: OwnedRoomId,
+		sender: OwnedUserId,
+		ts: MilliSecondsSinceUnixEpoch,
+		mimetype: Option<String>,
+		data: &[u8],
+	) -> Result<bool> {
+		let new = self.insert(
+			event_id,
+			room_id,
+			sender,
+			ts,
+			mimetype,
+			data,
+			State::Pending,
+		)?;
+		if new {
+			self.notify.notify_one();
+		}
+		Ok(new)
+	}
+
+	/// Keeps media that doesn't need playing, e.g. our own messages, for replaying
+	#[tracing::instrument(skip(self, data))]
+	pub fn remember(
+		&self,
+		event_id: OwnedEventId,
+		room_id: OwnedRoomId,
+		sender: OwnedUserId,
+		ts: MilliSecondsSinceUnixEpoch,
+		mimetype: Option<String>,
+		data: &[u8],
+	) -> Result<()> {
+		self.insert(event_id, room_id, sender, ts, mimetype, data, State::Played)?;
+		Ok(())
+	}
+
+	#[allow(clippy::too_many_arguments)]
+	fn insert(
+		&self,
+		event_id: OwnedEventId,
+		room_id: OwnedRoomId,
+		sender: OwnedUserId,
+		ts: MilliSecondsSinceUnixEpoch,
+		mimetype: Option<String>,
+		data: &[u8],
+		state: State,
+	) -> Result<bool> {
+		let mut index = self.index.lock().unwrap();
+		if index.entries.iter().any(|e| e.event_id == event_id) {
+			return Ok(false);
+		}
+		let file = event_id
+			.as_str()
+			.chars()
+			.map(|c| match c.is_ascii_alphanumeric() {
+				true => c,
+				false => '_',
+			})
+			.collect::<String>();
+		fs::write(self.dir.join(&file), data).context("Write queued media")?;
+		index.entries.push(Entry {
+			event_id,
+			room_id,
+			sender,
+			ts,
+			mimetype,
+			file,
+			state,
+		});
+		self.prune(&mut index);
+		self.persist(&index)?;
+		Ok(true)
+	}
+
+	/// Oldest pending entry. Stays pending until finished.
+	pub async fn next(&self) -> Entry {
+		loop {
+			let next = {
+				let index = self.index.lock().unwrap();
+				index
+					.entries
+					.iter()
+					.filter(|e| e.state == State::Pending)
+					.min_by_key(|e| e.ts)
+					.cloned()
+			};
+			match next {
+				Some(next) => return next,
+				None => self.notify.notified().await,
+			}
+		}
+	}
+
+	pub fn media(&self, entry: &Entry) -> Result<Vec<u8>> {
+		fs::read(self.dir.join(&entry.file)).context("Read queued media")
+	}
+
+	#[tracing::instrument(skip(self))]
+	pub fn finish(&self, event_id: &EventId, state: State) -> Result<()> {
+		let mut index = self.index.lock().unwrap();
+		if let Some(entry) = index.entries.iter_mut().find(|e| e.event_id == event_id) {
+			entry.state = state;
+		}
+		self.prune(&mut index);
+		self.persist(&index)
+	}
+
+	/// The last count finished messages that still have their media, oldest first
+	pub fn recent(&self, count: usize) -> Vec<Entry> {
+		let index = self.index.lock().unwrap();
+		let mut done = index
+			.entries
+			.iter()
+			.filter(|e| matches!(e.state, State::Played | State::Skipped))
+			.cloned()
+			.collect::<Vec<_>>();
+		done.sort_by_key(|e| e.ts);
+		done.split_off(done.len().saturating_sub(count))
+	}
+
+	fn prune(&self, index: &mut Index) {
+		let Index { entries, pruned } = index;
+		let done = entries.iter().filter(|e| e.state != State::Pending).count();
+		let mut excess = done.saturating_sub(KEEP_DONE);
+		let dir = &self.dir;
+		entries.retain(|e| {
+			if excess == 0 || e.state == State::Pending {
+				return true;
+			}
+			excess -= 1;
+			if let Err(error) = fs::remove_file(dir.join(&e.file)) {
+				warn!(?error, file = e.file, "Can't remove old media");
+			}
+			let mark = pruned.entry(e.room_id.clone()).or_insert(e.ts);
+			*mark = (*mark).max(e.ts);
+			false
+		});
+	}
+
+	fn persist(&self, index: &Index) -> Result<()> {
+		let file = self.dir.join(INDEX);
+		let tmp = file.with_extension("json.tmp");
+		fs::write(&tmp, serde_json::to_vec(index)?).context("Write queue index")?;
+		fs::rename(&tmp, &file).context("Replace queue index")?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use matrix_sdk::ruma::{room_id, user_id, UInt};
+
+	#[test]
+	fn pruned_stays_known() {
+		let dir = std::env::temp_dir().join(format!("gegensprech-queue-{}", std::process::id()));
+		let room = room_id!("!room:example.org");
+		let event = |i: u32| EventId::parse(format!("$event{i}:example.org")).unwrap();
+		let ts = |i: u32| MilliSecondsSinceUnixEpoch(UInt::from(1000 + i));
+		let queue = Queue::open(&dir).unwrap();
+		for i in 0..KEEP_DONE as u32 + 4 {
+			let user = user_id!("@someone:example.org").to_owned();
+			assert!(queue
+				.push(event(i), room.to_owned(), user, ts(i), None, b"")
+				.unwrap());
+			queue.finish(&event(i), State::Skipped).unwrap();
+		}
+		assert!(queue.sender(&event(0)).is_none(), "Should have been pruned");
+		assert!(queue.knows(room, &event(0), ts(0)));
+		assert!(!queue.knows(room, &event(99), ts(99)));
+		assert!(!queue.knows(room_id!("!other:example.org"), &event(0), ts(0)));
+		drop(queue);
+		let queue = Queue::open(&dir).unwrap();
+		assert!(queue.knows(room, &event(3), ts(3)));
+		fs::remove_dir_all(&dir).ok();
+	}
+}
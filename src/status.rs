@@ -5,35 +5,63 @@ use rppal::{
 	gpio::{Gpio, Level, OutputPin},
 	spi,
 };
+use serde::{Deserialize, Serialize};
 use smart_leds_trait::{SmartLedsWrite, RGB};
-use std::sync::Mutex;
+use std::{
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Mutex,
+	},
+	thread,
+	time::Duration,
+};
 use tracing::{error, warn};
 
 use crate::{
 	misc::{CallOnDrop, UndoOnDrop},
-	Hardware, RGBPins, SolderedCustom,
+	RGBPins,
 };
 
 structstruck::strike! {
 	#[strikethrough[derive(Debug, Clone)]]
-	struct Status {
+	pub(crate) struct Status {
 		send_status: bool,
 		catchup_status: bool,
 		mtx_status: #[derive(Copy, PartialEq)] pub enum {
 			Starting,
 			Good,
 			Disconnected,
+			LoggedOut,
 		},
 		audio_status: #[derive(Copy)] pub enum {
 			Recording,
 			Playing,
 			Idle,
 		},
+		/// Messages held back, and why
+		waiting: Option<#[derive(Copy, PartialEq)] pub enum Waiting {
+			Dnd,
+			OnDemand,
+		}>,
+		/// Brief feedback while keying Morse, overrides everything else
+		flash: Option<#[derive(Copy, PartialEq)] pub enum Flash {
+			Dot,
+			Dash,
+			/// The command exists
+			Found,
+			/// No such command
+			Unknown,
+		}>,
+		/// Set by commands, overrides everything else but flashes
+		pattern: Option<#[derive(Copy, PartialEq, Deserialize, Serialize)] #[serde(rename_all = "kebab-case")] pub enum LedPattern {
+			Blink,
+			Solid,
+		}>,
 		exited: bool,
 	}
 }
 
-trait Render {
+pub(crate) trait Render {
 	fn render(&mut self, status: &Status);
 }
 
@@ -41,11 +69,11 @@ impl Render for () {
 	fn render(&mut self, _status: &Status) {}
 }
 
-struct RGBLed([OutputPin; 3]);
+pub(crate) struct RGBLed([OutputPin; 3]);
 
 impl RGBLed {
 	#[tracing::instrument(skip(gpio))]
-	fn new(rgb: &RGBPins, gpio: &Gpio) -> Result<Box<RGBLed>> {
+	pub fn new(rgb: &RGBPins, gpio: &Gpio) -> Result<Box<RGBLed>> {
 		let RGBPins { r, b, g, ground } = rgb;
 		let rgb = [r, g, b];
 		for &ground in ground {
@@ -76,6 +104,28 @@ impl RGBLed {
 		let pending = status.send_status || status.catchup_status;
 		match status {
 			Status { exited: true, .. } => [Low, Low, Low],
+			Status {
+				flash: Some(flash), ..
+			} => match flash {
+				Flash::Dot | Flash::Dash => [Low, Low, High],
+				Flash::Found => [Low, High, Low],
+				Flash::Unknown => [High, Low, Low],
+			},
+			Status {
+				pattern: Some(LedPattern::Solid),
+				..
+			} => match BLINK.load(Ordering::Relaxed) {
+				// No color is left that nothing else uses, and red/blue doesn't alternate elsewhere
+				true => [High, Low, Low],
+				false => [Low, Low, High],
+			},
+			Status {
+				pattern: Some(LedPattern::Blink),
+				..
+			} => match BLINK.load(Ordering::Relaxed) {
+				true => [High, High, High],
+				false => [Low, Low, Low],
+			},
 			Status {
 				audio_status: Recording,
 				..
@@ -84,6 +134,20 @@ impl RGBLed {
 				audio_status: Playing,
 				..
 			} => [Low, High, Low],
+			Status {
+				mtx_status: LoggedOut,
+				..
+			} => [High, High, Low],
+			Status {
+				waiting: Some(waiting),
+				..
+			} => match (BLINK.load(Ordering::Relaxed), waiting) {
+				(true, Waiting::Dnd) => [Low, High, Low],
+				(false, Waiting::Dnd) => [Low, Low, Low],
+				// All eight colors are taken, alternating sets it apart
+				(true, Waiting::OnDemand) => [Low, Low, High],
+				(false, Waiting::OnDemand) => [Low, High, Low],
+			},
 			_ if matrix_meh && pending => [High, Low, High],
 			Status {
 				send_status: true, ..
@@ -122,17 +186,20 @@ mod led_color {
 	static H: u8 = 30; // Those LEDs are bit ridiculously bright…
 	color!(OFF, 0, 0, 0);
 	color!(WEAK_WHITE, 10, 10, 10);
+	color!(WHITE, H, H, H);
 	color!(YELLOW, H, H, 0);
 	color!(AMBER, H, H / 2, 0);
 	color!(PURPLE, H, 0, H);
 	color!(RED, H, 0, 0);
 	color!(GREEN, 0, H, 0);
 	color!(BLUE, 0, 0, H);
+	color!(CYAN, 0, H, H);
+	color!(PINK, H, 0, H / 2);
 }
 
-struct Seeed(Apa102<spi::Spi>);
+pub(crate) struct Seeed(Apa102<spi::Spi>);
 impl Seeed {
-	fn new() -> Result<Box<Seeed>> {
+	pub fn new() -> Result<Box<Seeed>> {
 		let spi = spi::Spi::new(
 			spi::Bus::Spi0,
 			spi::SlaveSelect::Ss1,
@@ -150,7 +217,19 @@ impl Render for Seeed {
 	fn render(&mut self, status: &Status) {
 		use led_color::*;
 		let mut data = [RGB::<u8>::default(); 3];
-		if !status.exited {
+		let blink = BLINK.load(Ordering::Relaxed);
+		if let (false, Some(flash)) = (status.exited, status.flash) {
+			data = match flash {
+				Flash::Dot | Flash::Dash => [BLUE; 3],
+				Flash::Found => [GREEN; 3],
+				Flash::Unknown => [RED; 3],
+			};
+		} else if let (false, Some(pattern)) = (status.exited, status.pattern) {
+			data = match (pattern, blink) {
+				(LedPattern::Solid, _) | (LedPattern::Blink, true) => [WHITE; 3],
+				(LedPattern::Blink, false) => [OFF; 3],
+			};
+		} else if !status.exited {
 			data[2] = match (status.send_status, status.catchup_status) {
 				(true, _) => PURPLE,
 				(_, true) => BLUE,
@@ -159,12 +238,17 @@ impl Render for Seeed {
 			data[1] = match status.audio_status {
 				AudioStatus::Recording => RED,
 				AudioStatus::Playing => GREEN,
-				AudioStatus::Idle => OFF,
+				AudioStatus::Idle => match status.waiting {
+					Some(Waiting::Dnd) => CYAN,
+					Some(Waiting::OnDemand) => PINK,
+					None => OFF,
+				},
 			};
 			data[0] = match status.mtx_status {
 				MtxStatus::Starting => YELLOW,
 				MtxStatus::Good => WEAK_WHITE,
 				MtxStatus::Disconnected => AMBER,
+				MtxStatus::LoggedOut => RED,
 			};
 		} else {
 			data = [OFF; 3];
@@ -185,6 +269,9 @@ impl Status {
 			catchup_status: false,
 			mtx_status: MtxStatus::Starting,
 			audio_status: AudioStatus::Idle,
+			waiting: None,
+			flash: None,
+			pattern: None,
 			exited: false,
 		}
 	}
@@ -205,8 +292,103 @@ pub(crate) fn audio(audio: AudioStatus) -> impl UndoOnDrop {
 	CallOnDrop::call(move || status(|status| status.audio_status = AudioStatus::Idle))
 }
 
+/// Phase for blinking, not part of Status so it doesn't spam PrintStatus
+static BLINK: AtomicBool = AtomicBool::new(false);
+static BLINKING: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn waiting(why: Waiting) -> impl UndoOnDrop {
+	status(|status| {
+		status.waiting = Some(why);
+		start_blinking();
+	});
+	CallOnDrop::call(move || status(|status| status.waiting = None))
+}
+
+/// Shows a pattern until dropped
+pub(crate) fn pattern(pattern: LedPattern) -> impl UndoOnDrop {
+	status(|status| {
+		status.pattern = Some(pattern);
+		start_blinking();
+	});
+	CallOnDrop::call(move || status(|status| status.pattern = None))
+}
+
+/// Which flash is current, older ones stop when they see a newer one
+static FLASHING: AtomicUsize = AtomicUsize::new(0);
+/// Between the flashes of a pattern
+const FLASH_GAP: Duration = Duration::from_millis(100);
+
+impl Flash {
+	/// How often, how long each
+	fn pattern(self) -> (usize, Duration) {
+		let ms = Duration::from_millis;
+		match self {
+			Flash::Dot => (1, ms(80)),
+			Flash::Dash => (1, ms(240)),
+			Flash::Found => (1, ms(400)),
+			Flash::Unknown => (3, ms(100)),
+		}
+	}
+}
+
+/// Shows feedback for a moment, doesn't block
+pub(crate) fn flash(flash: Flash) {
+	let id = FLASHING.fetch_add(1, Ordering::Relaxed) + 1;
+	let current = move || FLASHING.load(Ordering::Relaxed) == id;
+	thread::spawn(move || {
+		let (times, on) = flash.pattern();
+		for i in 0..times {
+			if i > 0 {
+				thread::sleep(FLASH_GAP);
+			}
+			let set = |to| {
+				status(|status| {
+					if current() {
+						status.flash = to;
+					}
+				})
+			};
+			set(Some(flash));
+			thread::sleep(on);
+			set(None);
+		}
+	});
+}
+
+/// Only call with the status locked
+fn start_blinking() {
+	if !BLINKING.swap(true, Ordering::Relaxed) {
+		thread::spawn(blink);
+	}
+}
+
+fn blink() {
+	loop {
+		thread::sleep(Duration::from_millis(500));
+		let mut done = false;
+		status(|status| {
+			// Decided under the status lock, so waiting() can't miss the exit
+			done = status.waiting.is_none() && status.pattern.is_none();
+			match done {
+				true => BLINKING.store(false, Ordering::Relaxed),
+				false => {
+					BLINK.fetch_xor(true, Ordering::Relaxed);
+				}
+			}
+		});
+		if done {
+			return;
+		}
+	}
+}
+
 pub(crate) fn mtx(mtx: MtxStatus) {
-	status(|status| status.mtx_status = mtx);
+	status(|status| {
+		// Sticks until restart, a login is needed to get out of it
+		if status.mtx_status != MtxStatus::LoggedOut {
+			status.mtx_status = mtx
+		}
+	});
 }
 
 pub(crate) fn send() -> impl UndoOnDrop {
@@ -216,13 +398,8 @@ pub(crate) fn send() -> impl UndoOnDrop {
 	})
 }
 
-#[tracing::instrument]
-pub(crate) fn init_from_args(args: &Hardware, gpio: &Gpio) -> Result<impl UndoOnDrop> {
-	let render: Box<dyn Render + Send> = match args {
-		Hardware::Seeed2Mic => Seeed::new()?,
-		Hardware::SolderedCustom(SolderedCustom { rgb: Some(rgb), .. }) => RGBLed::new(rgb, gpio)?,
-		Hardware::SolderedCustom(_) => Box::new(()),
-	};
+#[tracing::instrument(skip(render))]
+pub(crate) fn init(render: Box<dyn Render + Send>) -> impl UndoOnDrop {
 	if STATUS
 		.set(StatusIndicators(Mutex::new((render, Status::initial()))))
 		.is_err()
@@ -230,7 +407,13 @@ pub(crate) fn init_from_args(args: &Hardware, gpio: &Gpio) -> Result<impl UndoOn
 		error!("Can init status LEDs only once");
 	}
 	status(|_| ());
-	Ok(CallOnDrop::call(|| status(|status| status.exited = true)))
+	CallOnDrop::call(|| status(|status| status.exited = true))
+}
+
+/// Tests have no LEDs, and may init more than once
+#[cfg(test)]
+pub(crate) fn init_for_tests() {
+	STATUS.get_or_init(|| StatusIndicators(Mutex::new((Box::new(()), Status::initial()))));
 }
 
 pub(crate) fn caughtup(caughtup: bool) {
@@ -0,0 +1,240 @@
+use anyhow::{bail, Result};
+use matrix_sdk::ruma::OwnedRoomId;
+use std::{collections::HashMap, fmt::Display, fs, os::unix::fs::PermissionsExt, path::Path};
+
+use crate::{
+	cmd::{self, MorseWord},
+	config::{self, Settings},
+	mtx, SessionData,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Severity {
+	Warning,
+	Error,
+}
+
+struct Findings(Vec<(Severity, String)>);
+
+impl Findings {
+	fn add(
+		&mut self,
+		severity: Severity,
+		file: &Path,
+		at: Option<(usize, usize)>,
+		msg: impl Display,
+	) {
+		let at = match at {
+			Some((line, column)) => format!(":{line}:{column}"),
+			None => String::new(),
+		};
+		self.0
+			.push((severity, format!("{}{at}: {msg}", file.display())));
+	}
+	fn error(&mut self, file: &Path, at: Option<(usize, usize)>, msg: impl Display) {
+		self.add(Severity::Error, file, at, msg)
+	}
+	fn warn(&mut self, file: &Path, msg: impl Display) {
+		self.add(Severity::Warning, file, None, msg)
+	}
+	/// Reports a missing or non-executable binary
+	fn binary(&mut self, file: &Path, what: &str, cmd: &[String]) {
+		match cmd.first() {
+			None => self.error(file, None, format!("{what}: empty command")),
+			Some(bin) if !executable(bin) => self.error(
+				file,
+				None,
+				format!("{what}: {bin} not found or not executable"),
+			),
+			Some(_) => (),
+		}
+	}
+}
+
+fn yaml_at(e: &serde_yaml::Error) -> Option<(usize, usize)> {
+	e.location().map(|l| (l.line(), l.column()))
+}
+
+/// Like the shell would find it
+fn executable(bin: &str) -> bool {
+	let is_exe = |p: &Path| {
+		p.metadata()
+			.map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+			.unwrap_or(false)
+	};
+	if bin.contains('/') {
+		return is_exe(Path::new(bin));
+	}
+	std::env::var_os("PATH")
+		.map(|path| std::env::split_paths(&path).any(|dir| is_exe(&dir.join(bin))))
+		.unwrap_or(false)
+}
+
+fn settings(config_dir: &Path, found: &mut Findings) {
+	let file = &config_dir.join(config::SETTINGS);
+	let data = match fs::read(file) {
+		Ok(data) => data,
+		Err(_) if !file.exists() => return,
+		Err(e) => return found.error(file, None, e),
+	};
+	if let Err(e) = serde_yaml::from_slice::<Settings>(&data) {
+		return found.error(file, yaml_at(&e), e);
+	}
+	// Again through load, for the path resolution
+	let settings = match Settings::load(config_dir) {
+		Ok(settings) => settings,
+		Err(e) => return found.error(file, None, format!("{e:#}")),
+	};
+	let commands = [
+		("tts", settings.tts.as_deref()),
+		(
+			"transcribe.cmd",
+			settings.transcribe.as_ref().map(|t| &t.cmd[..]),
+		),
+		("audio.decoder", settings.audio.decoder.as_deref()),
+	];
+	for (what, cmd) in commands {
+		if let Some(cmd) = cmd {
+			found.binary(file, what, cmd);
+		}
+	}
+	if let Some(chime) = &settings.playback.chime {
+		let paths = chime.default.iter().chain(chime.senders.values());
+		for path in paths.filter(|p| !p.is_file()) {
+			found.error(file, None, format!("chime {path:?} doesn't exist"));
+		}
+	}
+}
+
+fn commands(config_dir: &Path, rooms: &[OwnedRoomId], found: &mut Findings) {
+	let file = &config_dir.join(cmd::MORSE_CMDS);
+	let data = match fs::read(file) {
+		Ok(data) => data,
+		Err(_) if !file.exists() => return found.warn(file, "doesn't exist, no commands"),
+		Err(e) => return found.error(file, None, e),
+	};
+	// Only the typed parse knows where a bad command is
+	let cmds = match serde_yaml::from_slice::<cmd::Cmds>(&data) {
+		Ok(cmds) => cmds,
+		Err(e) => return found.error(file, yaml_at(&e), e),
+	};
+	// And only the raw one sees different spellings of the same word
+	let raw = match serde_yaml::from_slice::<serde_yaml::Mapping>(&data) {
+		Ok(raw) => raw,
+		Err(e) => return found.error(file, yaml_at(&e), e),
+	};
+	let mut words = HashMap::<MorseWord, Vec<String>>::new();
+	for key in raw.keys() {
+		let key = key.as_str().unwrap_or_default().to_owned();
+		if let Ok(word) = MorseWord::try_from(std::borrow::Cow::from(&key[..])) {
+			words.entry(word).or_default().push(key);
+		}
+	}
+	for (word, keys) in &words {
+		if keys.len() > 1 {
+			found.error(file, None, format!("{keys:?} are all {word:?}"));
+		}
+		if word.0.first() != Some(&cmd::Morse::Short) {
+			// A long first press starts a recording
+			found.error(
+				file,
+				None,
+				format!("{word:?} can't be entered, commands start with ."),
+			);
+		}
+		for other in words
+			.keys()
+			.filter(|&o| o != word && o.0.starts_with(&word.0))
+		{
+			found.warn(
+				file,
+				format!("{word:?} is a prefix of {other:?}, needs a pause to be recognized"),
+			);
+		}
+	}
+	for (word, cmd) in &cmds {
+		if let Some(sub) = cmd.subprocess() {
+			found.binary(file, &format!("{word:?}"), sub);
+		}
+		match cmd.room() {
+			Some(room) if rooms.is_empty() => found.warn(
+				file,
+				format!("{word:?}: {room} not checked, pass --channel as to run"),
+			),
+			Some(room) if !rooms.contains(room) => found.error(
+				file,
+				None,
+				format!("{word:?}: {room} isn't one of the channels"),
+			),
+			_ => (),
+		}
+		if let Some(step) = cmd.step() {
+			for path in step.files() {
+				if !config_dir.join(path).is_file() {
+					found.error(file, None, format!("{word:?}: {path:?} doesn't exist"));
+				}
+			}
+		}
+	}
+}
+
+fn calibration(config_dir: &Path, found: &mut Findings) {
+	let file = &config_dir.join(config::CALIBRATION);
+	let data = match fs::read(file) {
+		Ok(data) => data,
+		Err(_) if !file.exists() => return,
+		Err(e) => return found.error(file, None, e),
+	};
+	let calibration = match serde_yaml::from_slice::<config::Calibration>(&data) {
+		Ok(calibration) => calibration,
+		Err(e) => return found.error(file, yaml_at(&e), e),
+	};
+	if let Err(e) = calibration.check() {
+		found.error(file, None, format!("{e:#}"));
+	}
+}
+
+fn session(config_dir: &Path, found: &mut Findings) {
+	let file = &config_dir.join(mtx::SESSION_PATH);
+	let data = match fs::read(file) {
+		Ok(data) => data,
+		Err(_) if !file.exists() => return found.error(file, None, "Not logged in"),
+		Err(e) => return found.error(file, None, e),
+	};
+	match serde_json::from_slice::<SessionData>(&data) {
+		Err(e) => found.error(file, Some((e.line(), e.column())), e),
+		Ok(session) if session.access_token.is_empty() => {
+			found.error(file, None, "Empty access token")
+		}
+		Ok(_) => (),
+	}
+	let mode = file.metadata().map(|m| m.permissions().mode()).unwrap_or(0);
+	if mode & 0o077 != 0 {
+		found.warn(
+			file,
+			format!("Readable by others (mode {:o})", mode & 0o777),
+		);
+	}
+}
+
+/// Checks all config files, prints what's wrong, fails if anything is an error
+pub(crate) fn run(config_dir: &Path, rooms: &[OwnedRoomId]) -> Result<()> {
+	let mut found = Findings(vec![]);
+	settings(config_dir, &mut found);
+	commands(config_dir, rooms, &mut found);
+	calibration(config_dir, &mut found);
+	session(config_dir, &mut found);
+	let errors = found
+		.0
+		.iter()
+		.filter(|(s, _)| *s == Severity::Error)
+		.count();
+	for (severity, msg) in &found.0 {
+		println!("{severity:?}: {msg}");
+	}
+	if errors > 0 {
+		bail!("{errors} errors in {}", config_dir.display());
+	}
+	println!("Config in {} looks good", config_dir.display());
+	Ok(())
+}